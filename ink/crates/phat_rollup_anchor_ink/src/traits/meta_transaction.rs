@@ -1,4 +1,6 @@
-use crate::traits::rollup_anchor::{RollupAnchor, RollupAnchorError, RollupCondEqMethodParams};
+use crate::traits::rollup_anchor::{
+    RollupAnchor, RollupAnchorError, RollupCondEqMethodParams, RollupCondMethodParams,
+};
 use ink::env::hash::{Blake2x256, HashOutput};
 use ink::prelude::vec::Vec;
 use openbrush::storage::Mapping;
@@ -134,6 +136,31 @@ pub trait MetaTransaction: Storage<Data> + EventBroadcaster + RollupAnchor {
 
         Ok(())
     }
+
+    #[ink(message)]
+    fn meta_tx_rollup_cond(
+        &mut self,
+        request: ForwardRequest,
+        signature: [u8; 65],
+    ) -> Result<(), MetaTransactionError> {
+        // check the signature
+        self.ensure_meta_tx_valid(&request, &signature)?;
+
+        // check the attestor role
+        self.check_attestor_role(request.from)?;
+
+        // decode the data
+        let data: RollupCondMethodParams = scale::Decode::decode(&mut request.data.as_slice())
+            .map_err(|_| RollupAnchorError::FailedToDecode)?;
+
+        // emit the event
+        self.emit_event_meta_tx_decoded();
+
+        // call the rollup
+        self.inner_rollup_cond(data.0, data.1, data.2)?;
+
+        Ok(())
+    }
 }
 
 pub trait EventBroadcaster {
@@ -149,6 +176,6 @@ fn hash_blake2b256(input: &[u8]) -> [u8; 32] {
 }
 
 /// Converts a compressed ECDSA public key to AccountId
-fn get_ecdsa_account_id(pub_key: &[u8; 33]) -> AccountId {
+pub(crate) fn get_ecdsa_account_id(pub_key: &[u8; 33]) -> AccountId {
     AccountId::from(hash_blake2b256(pub_key))
 }
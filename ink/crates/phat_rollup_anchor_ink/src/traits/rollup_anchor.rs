@@ -1,3 +1,5 @@
+use crate::traits::meta_transaction::get_ecdsa_account_id;
+use ink::env::hash::{Blake2x256, HashOutput};
 use ink::prelude::vec::Vec;
 pub use kv_session::traits::{Key, QueueIndex, Value};
 use openbrush::contracts::access_control::{self, AccessControlError, RoleType};
@@ -36,6 +38,20 @@ pub enum HandleActionInput {
     RevokeAttestor(AccountId),
 }
 
+/// Condition checked by `rollup_cond` before applying the updates and the actions
+///
+/// `Le` and `Ge` compare the value as a SCALE encoded `u32`. A missing value is treated as 0.
+#[derive(scale::Encode, scale::Decode, Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum Condition {
+    Eq(Key, Option<Value>),
+    Ne(Key, Option<Value>),
+    Exists(Key),
+    NotExists(Key),
+    Le(Key, u32),
+    Ge(Key, u32),
+}
+
 #[derive(Debug, Eq, PartialEq, scale::Encode, scale::Decode)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub enum RollupAnchorError {
@@ -44,6 +60,7 @@ pub enum RollupAnchorError {
     FailedToDecode,
     UnsupportedAction,
     AccessControlError(AccessControlError),
+    InvalidSignature,
 }
 
 /// convertor from AccessControlError to RollupAnchorError
@@ -59,6 +76,19 @@ pub type RollupCondEqMethodParams = (
     Vec<HandleActionInput>,
 );
 
+pub type RollupCondMethodParams = (
+    Vec<Condition>,
+    Vec<(Key, Option<Value>)>,
+    Vec<HandleActionInput>,
+);
+
+pub type RollupSignedMethodParams = (
+    Vec<Condition>,
+    Vec<Vec<u8>>,
+    Vec<(Key, Option<Value>)>,
+    [u8; 65],
+);
+
 macro_rules! get_key {
     ($id:ident) => {
         [QUEUE_PREFIX, &$id.encode()].concat()
@@ -100,6 +130,12 @@ pub trait RollupAnchor:
         self.inner_get_value(&key)
     }
 
+    /// Gets the values of the keys in one call, in the order of the keys
+    #[ink(message)]
+    fn get_values(&self, keys: Vec<Key>) -> Vec<Option<Value>> {
+        keys.iter().map(|key| self.inner_get_value(key)).collect()
+    }
+
     fn inner_get_value(&self, key: &Key) -> Option<Value> {
         self.data::<Data>().kv_store.get(key)
     }
@@ -201,6 +237,50 @@ pub trait RollupAnchor:
         self.inner_rollup_cond_eq(conditions, updates, actions)
     }
 
+    #[ink(message)]
+    #[openbrush::modifiers(access_control::only_role(ATTESTOR_ROLE))]
+    fn rollup_cond(
+        &mut self,
+        conditions: Vec<Condition>,
+        updates: Vec<(Key, Option<Value>)>,
+        actions: Vec<HandleActionInput>,
+    ) -> Result<(), RollupAnchorError> {
+        self.inner_rollup_cond(conditions, updates, actions)
+    }
+
+    /// Applies a rollup tx signed by an attestor. Anyone can submit it.
+    ///
+    /// The actions are the SCALE encoded `HandleActionInput`s. The signature is an ecdsa signature
+    /// of the blake2b-256 hash of the SCALE encoded `(contract_id, conditions, actions, updates)`,
+    /// i.e. the signed `RollupTx` of the Phat Contract. It doesn't prevent the same tx from being
+    /// submitted twice, so the tx must be protected by its conditions.
+    #[ink(message)]
+    fn rollup_signed(
+        &mut self,
+        conditions: Vec<Condition>,
+        actions: Vec<Vec<u8>>,
+        updates: Vec<(Key, Option<Value>)>,
+        signature: [u8; 65],
+    ) -> Result<(), RollupAnchorError> {
+        let to = Self::env().account_id();
+        let mut hash = <Blake2x256 as HashOutput>::Type::default();
+        ink::env::hash_encoded::<Blake2x256, _>(&(to, &conditions, &actions, &updates), &mut hash);
+
+        let mut public_key = [0u8; 33];
+        ink::env::ecdsa_recover(&signature, &hash, &mut public_key)
+            .map_err(|_| RollupAnchorError::InvalidSignature)?;
+        self.check_attestor_role(get_ecdsa_account_id(&public_key))?;
+
+        let actions = actions
+            .into_iter()
+            .map(|action| {
+                HandleActionInput::decode(&mut action.as_slice())
+                    .map_err(|_| RollupAnchorError::FailedToDecode)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.inner_rollup_cond(conditions, updates, actions)
+    }
+
     fn check_attestor_role(&self, attestor: AccountId) -> Result<(), RollupAnchorError> {
         if !self.has_role(ATTESTOR_ROLE, Some(attestor)) {
             return Err(RollupAnchorError::AccessControlError(
@@ -216,21 +296,25 @@ pub trait RollupAnchor:
         conditions: Vec<(Key, Option<Value>)>,
         updates: Vec<(Key, Option<Value>)>,
         actions: Vec<HandleActionInput>,
+    ) -> Result<(), RollupAnchorError> {
+        let conditions = conditions
+            .into_iter()
+            .map(|(key, value)| Condition::Eq(key, value))
+            .collect();
+        self.inner_rollup_cond(conditions, updates, actions)
+    }
+
+    fn inner_rollup_cond(
+        &mut self,
+        conditions: Vec<Condition>,
+        updates: Vec<(Key, Option<Value>)>,
+        actions: Vec<HandleActionInput>,
     ) -> Result<(), RollupAnchorError> {
         // check the conditions
         for cond in conditions {
-            let key = cond.0;
-            let current_value = self.inner_get_value(&key);
-            let expected_value = cond.1;
-            match (current_value, expected_value) {
-                (None, None) => {}
-                (Some(v1), Some(v2)) => {
-                    if v1.ne(&v2) {
-                        // condition is not met
-                        return Err(RollupAnchorError::ConditionNotMet);
-                    }
-                }
-                (_, _) => return Err(RollupAnchorError::ConditionNotMet),
+            if !self.check_condition(&cond)? {
+                // condition is not met
+                return Err(RollupAnchorError::ConditionNotMet);
             }
         }
 
@@ -247,6 +331,26 @@ pub trait RollupAnchor:
         Ok(())
     }
 
+    fn check_condition(&self, cond: &Condition) -> Result<bool, RollupAnchorError> {
+        let decode_u32 = |key: &Key| -> Result<u32, RollupAnchorError> {
+            match self.inner_get_value(key) {
+                Some(v) => {
+                    u32::decode(&mut v.as_slice()).map_err(|_| RollupAnchorError::FailedToDecode)
+                }
+                None => Ok(0),
+            }
+        };
+        let met = match cond {
+            Condition::Eq(key, expected) => self.inner_get_value(key) == *expected,
+            Condition::Ne(key, expected) => self.inner_get_value(key) != *expected,
+            Condition::Exists(key) => self.inner_get_value(key).is_some(),
+            Condition::NotExists(key) => self.inner_get_value(key).is_none(),
+            Condition::Le(key, n) => decode_u32(key)? <= *n,
+            Condition::Ge(key, n) => decode_u32(key)? >= *n,
+        };
+        Ok(met)
+    }
+
     fn handle_action(&mut self, input: HandleActionInput) -> Result<(), RollupAnchorError> {
         match input {
             HandleActionInput::Reply(action) => self.on_message_received(action)?,
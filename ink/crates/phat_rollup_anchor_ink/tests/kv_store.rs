@@ -43,6 +43,27 @@ fn test_set_encoded_values() {
     }
 }
 
+#[ink::test]
+fn test_get_values() {
+    let accounts = accounts();
+    let mut contract = MyContract::new(accounts.alice);
+
+    let key_1 = b"0x123".to_vec();
+    let some_value_1 = "0x456".encode();
+    contract.set_value(&key_1, Some(&some_value_1));
+
+    let key_2 = b"0x124".to_vec();
+    let some_value_2 = "0x457".encode();
+    contract.set_value(&key_2, Some(&some_value_2));
+
+    let key_3 = b"0x125".to_vec();
+    assert_eq!(
+        contract.get_values(vec![key_2, key_3, key_1]),
+        vec![Some(some_value_2), None, Some(some_value_1)]
+    );
+    assert!(contract.get_values(vec![]).is_empty());
+}
+
 #[ink::test]
 fn test_update_same_key() {
     let accounts = accounts();
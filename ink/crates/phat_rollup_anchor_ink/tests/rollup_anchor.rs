@@ -1,7 +1,12 @@
+use ink::env::test::set_callee;
+use ink::env::DefaultEnvironment;
 use ink::prelude::vec::Vec;
+use ink_e2e::subxt::tx::Signer;
+use ink_e2e::PolkadotConfig;
 use openbrush::contracts::access_control;
 use openbrush::contracts::access_control::AccessControl;
 use openbrush::test_utils::{accounts, change_caller};
+use openbrush::traits::AccountId;
 use phat_rollup_anchor_ink::traits::rollup_anchor::*;
 use scale::Encode;
 
@@ -53,6 +58,107 @@ fn test_conditions() {
     );
 }
 
+#[ink::test]
+fn test_rollup_cond() {
+    let accounts = accounts();
+    let mut contract = MyContract::new(accounts.alice);
+
+    let key = 123u8.encode();
+
+    // the key doesn't exist yet
+    let conditions = vec![
+        Condition::NotExists(key.clone()),
+        Condition::Ne(key.clone(), Some(1u32.encode())),
+        Condition::Le(key.clone(), 0),
+    ];
+    let updates = vec![(key.clone(), Some(5u32.encode()))];
+    assert_eq!(contract.rollup_cond(conditions, updates, vec![]), Ok(()));
+
+    // the key exists now
+    let conditions = vec![
+        Condition::Exists(key.clone()),
+        Condition::Eq(key.clone(), Some(5u32.encode())),
+        Condition::Ge(key.clone(), 5),
+        Condition::Le(key.clone(), 5),
+    ];
+    assert_eq!(contract.rollup_cond(conditions, vec![], vec![]), Ok(()));
+
+    // failed conditions
+    for cond in [
+        Condition::NotExists(key.clone()),
+        Condition::Ne(key.clone(), Some(5u32.encode())),
+        Condition::Ge(key.clone(), 6),
+        Condition::Le(key.clone(), 4),
+    ] {
+        assert_eq!(
+            contract.rollup_cond(vec![cond], vec![], vec![]),
+            Err(RollupAnchorError::ConditionNotMet)
+        );
+    }
+
+    // the value cannot be decoded as u32
+    let updates = vec![(key.clone(), Some(1u8.encode()))];
+    assert_eq!(contract.rollup_cond(vec![], updates, vec![]), Ok(()));
+    assert_eq!(
+        contract.rollup_cond(vec![Condition::Ge(key, 0)], vec![], vec![]),
+        Err(RollupAnchorError::FailedToDecode)
+    );
+}
+
+#[ink::test]
+fn test_rollup_signed() {
+    let contract_address = AccountId::from([0xFF as u8; 32]);
+    set_callee::<DefaultEnvironment>(contract_address);
+
+    // the attestor is the ecdsa account of Alice
+    let keypair = subxt_signer::ecdsa::dev::alice();
+    let attestor = AccountId::from(Signer::<PolkadotConfig>::account_id(&keypair).0);
+    let accounts = accounts();
+    let mut contract = MyContract::new(attestor);
+
+    let key = 123u8.encode();
+    let conditions = vec![Condition::NotExists(key.clone())];
+    let actions = vec![HandleActionInput::Reply(5u8.encode()).encode()];
+    let updates = vec![(key.clone(), Some(1u32.encode()))];
+
+    // Alice signs the tx
+    let message = (contract_address, &conditions, &actions, &updates).encode();
+    let signature = keypair.sign(&message).0;
+
+    // the tx can be relayed by anyone
+    change_caller(accounts.bob);
+    assert_eq!(
+        contract.rollup_signed(
+            conditions.clone(),
+            actions.clone(),
+            updates.clone(),
+            signature
+        ),
+        Ok(())
+    );
+    assert_eq!(contract.get_value(key.clone()), Some(1u32.encode()));
+
+    // the same tx cannot be applied twice thanks to its condition
+    assert_eq!(
+        contract.rollup_signed(
+            conditions.clone(),
+            actions.clone(),
+            updates.clone(),
+            signature
+        ),
+        Err(RollupAnchorError::ConditionNotMet)
+    );
+
+    // a tampered tx is not signed by the attestor
+    let updates = vec![(key, Some(2u32.encode()))];
+    assert_eq!(
+        contract.rollup_signed(conditions, actions, updates, signature),
+        Err(RollupAnchorError::AccessControlError(
+            access_control::AccessControlError::MissingRole
+        ))
+    );
+}
+
 #[ink::test]
 fn test_action_pop_to() {
     let accounts = accounts();
@@ -16,7 +16,14 @@ mod evm_price_feed {
     // To enable `(result).log_err("Reason")?`
     use pink::ResultExt;
 
-    use phat_offchain_rollup::{clients::evm::EvmRollupClient, Action};
+    use phat_offchain_rollup::{
+        clients::{
+            commit_and_submit,
+            evm::{EvmRollupClient, ReceiptTracker, TxOutcome},
+            ClientOptions, RollupClient,
+        },
+        Action, ErrorKind,
+    };
 
     // Defined in TestOracle.sol
     const TYPE_RESPONSE: u32 = 0;
@@ -46,10 +53,11 @@ mod evm_price_feed {
         token1: String,
         /// Submit price feed as this feed id
         feed_id: u32,
+        /// The RPC endpoints to fail over to, in order
+        fallback_rpcs: Vec<String>,
     }
 
     #[derive(Encode, Decode, Debug)]
-    #[repr(u8)]
     #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
     pub enum Error {
         BadOrigin,
@@ -57,13 +65,16 @@ mod evm_price_feed {
         InvalidKeyLength,
         InvalidAddressLength,
         NoRequestInQueue,
-        FailedToCreateClient,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToCreateClient(ErrorKind),
         FailedToCommitTx,
         FailedToFetchPrice,
 
-        FailedToGetStorage,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToGetStorage(ErrorKind),
         FailedToCreateTransaction,
-        FailedToSendTransaction,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToSendTransaction(ErrorKind),
         FailedToGetBlockHash,
         FailedToDecode,
         InvalidRequest,
@@ -71,7 +82,8 @@ mod evm_price_feed {
 
     impl From<Error> for U256 {
         fn from(err: Error) -> U256 {
-            (err as u8).into()
+            // The variant index, without the error kind
+            err.encode()[0].into()
         }
     }
 
@@ -118,10 +130,20 @@ mod evm_price_feed {
                 token0,
                 token1,
                 feed_id,
+                fallback_rpcs: Default::default(),
             });
             Ok(())
         }
 
+        /// Sets the RPC endpoints to fail over to when the configured one is down (admin only)
+        #[ink(message)]
+        pub fn set_fallback_rpcs(&mut self, rpcs: Vec<String>) -> Result<()> {
+            self.ensure_owner()?;
+            let config = self.config.as_mut().ok_or(Error::NotConfigured)?;
+            config.fallback_rpcs = rpcs;
+            Ok(())
+        }
+
         /// Transfers the ownership of the contract (admin only)
         #[ink(message)]
         pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
@@ -192,9 +214,9 @@ mod evm_price_feed {
 
             // An offchain rollup contract will get a dedicated kv store on the target blockchain.
             // The kv store and the request queue can be accessed by the Phat Contract by:
-            // - client.session.get(key)
-            // - client.session.put(key, value)
-            // - client.session.pop()
+            // - client.get(key)
+            // - client.put(key, value)
+            // - client.pop()
             //
             // Note that all of the read, write, and custom actions are grouped as a transaction,
             // which is applied on the target blockchain atomically.
@@ -208,22 +230,40 @@ mod evm_price_feed {
         /// Processes a price request by a rollup transaction
         #[ink(message)]
         pub fn answer_price(&self) -> Result<Option<Vec<u8>>> {
+            self.answer_prices(1)
+        }
+
+        /// Processes up to `max_requests` price requests by a single rollup transaction
+        ///
+        /// The replies are submitted together, and the queue is marked processed once.
+        #[ink(message)]
+        pub fn answer_prices(&self, max_requests: u32) -> Result<Option<Vec<u8>>> {
             use ethabi::Token;
             let config = self.ensure_configured()?;
             let mut client = connect(&config)?;
-            let action = match Self::answer_price_inner(&mut client)? {
-                PriceReponse::Response(rid, price) => ethabi::encode(&[
-                    Token::Uint(TYPE_RESPONSE.into()),
-                    Token::Uint(rid),
-                    Token::Uint(price.into()),
-                ]),
-                PriceReponse::Error(rid, error) => ethabi::encode(&[
-                    Token::Uint(TYPE_ERROR.into()),
-                    Token::Uint(rid.unwrap_or_default()),
-                    Token::Uint(error.into()),
-                ]),
-            };
-            client.action(Action::Reply(action));
+            // Get the requests if present
+            let requests = client
+                .pop_many(max_requests as usize)
+                .log_err("answer_price: failed to read queue")
+                .map_err(|e| Error::FailedToGetStorage(e.kind()))?;
+            if requests.is_empty() {
+                return Err(Error::NoRequestInQueue);
+            }
+            for raw_req in requests {
+                let action = match Self::answer_request(&raw_req)? {
+                    PriceReponse::Response(rid, price) => ethabi::encode(&[
+                        Token::Uint(TYPE_RESPONSE.into()),
+                        Token::Uint(rid),
+                        Token::Uint(price.into()),
+                    ]),
+                    PriceReponse::Error(rid, error) => ethabi::encode(&[
+                        Token::Uint(TYPE_ERROR.into()),
+                        Token::Uint(rid.unwrap_or_default()),
+                        Token::Uint(error.into()),
+                    ]),
+                };
+                client.action(Action::Reply(action));
+            }
             maybe_submit_tx(client, &config)
         }
 
@@ -249,6 +289,7 @@ mod evm_price_feed {
                 token0: Default::default(),
                 token1: Default::default(),
                 feed_id,
+                fallback_rpcs: Default::default(),
             };
             let mut client = connect(&custom_config)?;
             let payload = ethabi::encode(&[
@@ -260,18 +301,23 @@ mod evm_price_feed {
             maybe_submit_tx(client, &custom_config)
         }
 
-        fn answer_price_inner(client: &mut EvmRollupClient) -> Result<PriceReponse> {
+        /// Reports the outcomes of the submitted rollup txs resolved since the last call
+        ///
+        /// Call it periodically to find out the rollup txs reverted or dropped.
+        #[ink(message)]
+        pub fn poll_receipts(&self) -> Result<Vec<([u8; 32], TxOutcome)>> {
+            let config = self.ensure_configured()?;
+            ReceiptTracker::new(&config.rpc)
+                .with_fallback_rpcs(&config.fallback_rpcs)
+                .poll()
+                .log_err("failed to poll receipts")
+                .map_err(|e| Error::FailedToGetStorage(e.kind()))
+        }
+
+        fn answer_request(raw_req: &[u8]) -> Result<PriceReponse> {
             use ethabi::{ParamType, Token};
-            use pink_kv_session::traits::QueueSession;
-            // Get a request if presents
-            let raw_req = client
-                .session()
-                .pop()
-                .log_err("answer_price: failed to read queue")
-                .or(Err(Error::FailedToGetStorage))?
-                .ok_or(Error::NoRequestInQueue)?;
             // Decode the queue data by ethabi (u256, bytes)
-            let Ok(decoded) = ethabi::decode(&[ParamType::Uint(32), ParamType::Bytes], &raw_req) else {
+            let Ok(decoded) = ethabi::decode(&[ParamType::Uint(32), ParamType::Bytes], raw_req) else {
                 return Ok(PriceReponse::Error(None, Error::FailedToDecode))
             };
             let [Token::Uint(rid), Token::Bytes(pair)] = decoded.as_slice() else {
@@ -322,36 +368,24 @@ mod evm_price_feed {
 
     fn connect(config: &Config) -> Result<EvmRollupClient> {
         let anchor_addr: H160 = config.anchor_addr.into();
-        EvmRollupClient::new(&config.rpc, anchor_addr)
+        let options = ClientOptions::default().with_fallback_rpcs(&config.fallback_rpcs);
+        EvmRollupClient::new_with_options(&config.rpc, anchor_addr, &options)
             .log_err("failed to create rollup client")
-            .or(Err(Error::FailedToCreateClient))
+            .map_err(|e| Error::FailedToCreateClient(e.kind()))
     }
 
     fn maybe_submit_tx(client: EvmRollupClient, config: &Config) -> Result<Option<Vec<u8>>> {
-        use pink_web3::keys::pink::KeyPair;
-        let maybe_submittable = client
-            .commit()
-            .log_err("failed to commit")
-            .or(Err(Error::FailedToCommitTx))?;
-        if let Some(submittable) = maybe_submittable {
-            let attest_pair = KeyPair::from(config.attest_key);
-            let tx_id = if let Some(sender_key) = config.sender_key {
-                // Prefer to meta-tx
-                let sender_pair = KeyPair::from(sender_key);
-                submittable
-                    .submit_meta_tx(&attest_pair, &sender_pair)
-                    .log_err("failed to submit rollup meta-tx")
-                    .or(Err(Error::FailedToSendTransaction))?
-            } else {
-                // Fallback to account-based authentication
-                submittable
-                    .submit(attest_pair)
-                    .log_err("failed to submit rollup tx")
-                    .or(Err(Error::FailedToSendTransaction))?
-            };
-            return Ok(Some(tx_id));
+        // Prefer to meta-tx if `sender_key` is set, or fallback to account-based authentication
+        let tx_id = commit_and_submit(client, &config.attest_key, config.sender_key.as_ref())
+            .log_err("failed to submit rollup tx")
+            .map_err(|e| Error::FailedToSendTransaction(e.kind()))?;
+        if let Some(tx_id) = &tx_id {
+            // Follow up the outcome in `poll_receipts()`
+            let _ = ReceiptTracker::new(&config.rpc)
+                .track(tx_id)
+                .log_err("failed to track the rollup tx");
         }
-        Ok(None)
+        Ok(tx_id)
     }
 
     // Define the structures to parse json like `{"token0":{"token1":1.23}}`
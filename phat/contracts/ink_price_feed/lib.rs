@@ -13,7 +13,14 @@ mod ink_price_feed {
     use scale::{Decode, Encode};
     use serde::Deserialize;
 
-    use phat_offchain_rollup::clients::ink::{Action, ContractId, InkRollupClient};
+    use phat_offchain_rollup::{
+        clients::{
+            commit_and_submit,
+            ink::{Action, ContractId, InkRollupClient},
+            ClientOptions,
+        },
+        ErrorKind,
+    };
 
     pub type TradingPairId = u32;
 
@@ -69,10 +76,11 @@ mod ink_price_feed {
         contract_id: ContractId,
         /// Key for sending out the rollup meta-tx. None to fallback to the wallet based auth.
         sender_key: Option<[u8; 32]>,
+        /// The RPC endpoints to fail over to, in order
+        fallback_rpcs: Vec<String>,
     }
 
     #[derive(Encode, Decode, Debug)]
-    #[repr(u8)]
     #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
     pub enum Error {
         BadOrigin,
@@ -80,7 +88,8 @@ mod ink_price_feed {
         InvalidKeyLength,
         InvalidAddressLength,
         NoRequestInQueue,
-        FailedToCreateClient,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToCreateClient(ErrorKind),
         FailedToCommitTx,
         FailedToFetchPrice,
 
@@ -90,16 +99,17 @@ mod ink_price_feed {
         FailedToGetBlockHash,
         FailedToDecode,
         InvalidRequest,
-        FailedToCallRollup,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToCallRollup(ErrorKind),
     }
 
     type Result<T> = core::result::Result<T, Error>;
 
     impl From<phat_offchain_rollup::Error> for Error {
         fn from(error: phat_offchain_rollup::Error) -> Self {
-            error!("error in the rollup: {:?}", error);
-            debug_println!("error in the rollup: {:?}", error);
-            Error::FailedToCallRollup
+            error!("error in the rollup: {}", error);
+            debug_println!("error in the rollup: {}", error);
+            Error::FailedToCallRollup(error.kind())
         }
     }
 
@@ -196,10 +206,20 @@ mod ink_price_feed {
                     Some(key) => Some(key.try_into().or(Err(Error::InvalidKeyLength))?),
                     None => None,
                 },
+                fallback_rpcs: Default::default(),
             });
             Ok(())
         }
 
+        /// Sets the RPC endpoints to fail over to when the configured one is down (admin only)
+        #[ink(message)]
+        pub fn set_fallback_rpcs(&mut self, rpcs: Vec<String>) -> Result<()> {
+            self.ensure_owner()?;
+            let config = self.config.as_mut().ok_or(Error::NotConfigured)?;
+            config.fallback_rpcs = rpcs;
+            Ok(())
+        }
+
         /// Transfers the ownership of the contract (admin only)
         #[ink(message)]
         pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
@@ -289,18 +309,30 @@ mod ink_price_feed {
         /// Processes a price request by a rollup transaction
         #[ink(message)]
         pub fn answer_price(&self) -> Result<Option<Vec<u8>>> {
+            self.answer_prices(1)
+        }
+
+        /// Processes up to `max_requests` price requests by a single rollup transaction
+        ///
+        /// The replies are submitted together, and the queue is marked processed once.
+        #[ink(message)]
+        pub fn answer_prices(&self, max_requests: u32) -> Result<Option<Vec<u8>>> {
             let config = self.ensure_configured()?;
             let mut client = connect(config)?;
 
-            // Get a request if presents
-            let request: PriceRequestMessage = client
-                .pop()
-                .log_err("answer_price: failed to read queue")?
-                .ok_or(Error::NoRequestInQueue)?;
+            // Get the requests if present
+            let requests: Vec<PriceRequestMessage> = client
+                .pop_many(max_requests as usize)
+                .log_err("answer_price: failed to read queue")?;
+            if requests.is_empty() {
+                return Err(Error::NoRequestInQueue);
+            }
 
-            let response = Self::handle_request(&request)?;
-            // Attach an action to the tx by:
-            client.action(Action::Reply(response.encode()));
+            for request in &requests {
+                let response = Self::handle_request(request)?;
+                // Attach an action to the tx by:
+                client.action(Action::Reply(response.encode()));
+            }
 
             maybe_submit_tx(client, &self.attest_key, config.sender_key.as_ref())
         }
@@ -326,6 +358,7 @@ mod ink_price_feed {
                     Some(key) => Some(key.try_into().or(Err(Error::InvalidKeyLength))?),
                     None => None,
                 },
+                fallback_rpcs: Default::default(),
             };
 
             let mut client = connect(config)?;
@@ -393,14 +426,16 @@ mod ink_price_feed {
     }
 
     fn connect(config: &Config) -> Result<InkRollupClient> {
-        InkRollupClient::new(
+        let options = ClientOptions::default().with_fallback_rpcs(&config.fallback_rpcs);
+        InkRollupClient::new_with_options(
             &config.rpc,
             config.pallet_id,
             config.call_id,
             &config.contract_id,
+            &options,
         )
         .log_err("failed to create rollup client")
-        .or(Err(Error::FailedToCreateClient))
+        .map_err(|e| Error::FailedToCreateClient(e.kind()))
     }
 
     fn maybe_submit_tx(
@@ -408,26 +443,9 @@ mod ink_price_feed {
         attest_key: &[u8; 32],
         sender_key: Option<&[u8; 32]>,
     ) -> Result<Option<Vec<u8>>> {
-        let maybe_submittable = client
-            .commit()
-            .log_err("failed to commit")
-            .map_err(|_| Error::FailedToCommitTx)?;
-
-        if let Some(submittable) = maybe_submittable {
-            let tx_id = if let Some(sender_key) = sender_key {
-                // Prefer to meta-tx
-                submittable
-                    .submit_meta_tx(attest_key, sender_key)
-                    .log_err("failed to submit rollup meta-tx")?
-            } else {
-                // Fallback to account-based authentication
-                submittable
-                    .submit(attest_key)
-                    .log_err("failed to submit rollup tx")?
-            };
-            return Ok(Some(tx_id));
-        }
-        Ok(None)
+        // Prefer to meta-tx if `sender_key` is set
+        Ok(commit_and_submit(client, attest_key, sender_key)
+            .log_err("failed to submit rollup tx")?)
     }
 
     // Define the structures to parse json like `{"token0":{"token1":1.23}}`
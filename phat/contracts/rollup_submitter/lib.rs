@@ -0,0 +1,315 @@
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+extern crate alloc;
+
+/// Submits the rollup txs produced by the `RollupHandler` contracts
+///
+/// The business contracts implement `RollupHandler` and never hold any chain key. The submitter
+/// calls `handle_rollup()` of each registered handler in `poll()`, and submits the returned
+/// `RollupResult` to its target with its own submission key.
+#[ink::contract(env = pink_extension::PinkEnvironment)]
+mod rollup_submitter {
+    use alloc::vec::Vec;
+    use ink::codegen::TraitCallBuilder;
+    use ink::env::call::FromAccountId;
+    use ink::storage::{traits::StorageLayout, Mapping};
+    use pink_extension as pink;
+    use pink_extension::chain_extension::signing;
+    use pink_web3::keys::pink::KeyPair;
+    use scale::{Decode, Encode};
+
+    use phat_offchain_rollup::{ErrorKind, RollupHandlerForwarder, RollupResult, RollupTarget};
+
+    #[ink(storage)]
+    pub struct RollupSubmitter {
+        owner: AccountId,
+        /// Key for submitting the rollup txs to all the anchors
+        submit_key: [u8; 32],
+        num_handlers: u32,
+        handlers: Mapping<u32, HandlerConfig>,
+        active_handlers: Vec<u32>,
+    }
+
+    #[derive(Encode, Decode, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo, StorageLayout))]
+    pub struct HandlerConfig {
+        /// The contract implementing `RollupHandler`
+        handler: AccountId,
+        /// The only anchor the handler may submit to
+        ///
+        /// It's pinned by the owner, so a handler can't direct the submission key to an arbitrary
+        /// RPC endpoint or contract.
+        target: RollupTarget,
+        enabled: bool,
+    }
+
+    #[derive(Encode, Decode, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub enum Error {
+        BadOrigin,
+        HandlerNotFound,
+        NotChanged,
+        InvalidKeyLength,
+        FailedToCallHandler,
+        HandlerReturnedError(Vec<u8>),
+        TargetMismatch,
+        /// The submission failed. The kind tells if it's worth retrying.
+        FailedToSubmitTx(ErrorKind),
+    }
+
+    type Result<T> = core::result::Result<T, Error>;
+
+    impl From<phat_offchain_rollup::Error> for Error {
+        fn from(error: phat_offchain_rollup::Error) -> Self {
+            pink::error!("error in the rollup: {}", error);
+            Error::FailedToSubmitTx(error.kind())
+        }
+    }
+
+    impl RollupSubmitter {
+        #[ink(constructor)]
+        pub fn default() -> Self {
+            Self {
+                owner: Self::env().caller(),
+                submit_key: Self::derive_submit_key(),
+                num_handlers: 0,
+                handlers: Default::default(),
+                active_handlers: Default::default(),
+            }
+        }
+
+        /// Gets the owner of the contract
+        #[ink(message)]
+        pub fn owner(&self) -> AccountId {
+            self.owner
+        }
+
+        /// Gets the sr25519 public key of the submission key, used by the Substrate and ink! txs
+        #[ink(message)]
+        pub fn get_submit_address(&self) -> Vec<u8> {
+            signing::get_public_key(&self.submit_key, signing::SigType::Sr25519)
+        }
+
+        /// Gets the EVM address of the submission key
+        #[ink(message)]
+        pub fn get_submit_address_evm(&self) -> [u8; 20] {
+            use pink_web3::signing::Key;
+            KeyPair::from(self.submit_key).address().into()
+        }
+
+        /// Sets the submission key. `None` to restore the derived key.
+        ///
+        /// For dev purpose.
+        #[ink(message)]
+        pub fn set_submit_key(&mut self, submit_key: Option<Vec<u8>>) -> Result<()> {
+            self.ensure_owner()?;
+            self.submit_key = match submit_key {
+                Some(key) => key.try_into().or(Err(Error::InvalidKeyLength))?,
+                None => Self::derive_submit_key(),
+            };
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_num_handlers(&self) -> u32 {
+            self.num_handlers
+        }
+
+        #[ink(message)]
+        pub fn get_handler(&self, id: u32) -> Result<HandlerConfig> {
+            self.ensure_handler(id)
+        }
+
+        #[ink(message)]
+        pub fn get_active_handlers(&self) -> Vec<u32> {
+            self.active_handlers.clone()
+        }
+
+        /// Registers a `RollupHandler` contract
+        ///
+        /// The rollup txs of the handler are only submitted to `target`. A result targeting
+        /// another anchor is rejected with `Error::TargetMismatch`.
+        #[ink(message)]
+        pub fn add_handler(&mut self, handler: AccountId, target: RollupTarget) -> Result<u32> {
+            self.ensure_owner()?;
+            let id = self.num_handlers;
+            self.num_handlers += 1;
+            self.handlers.insert(
+                id,
+                &HandlerConfig {
+                    handler,
+                    target,
+                    enabled: true,
+                },
+            );
+            self.active_handlers.push(id);
+            Ok(id)
+        }
+
+        #[ink(message)]
+        pub fn set_handler_target(&mut self, id: u32, target: RollupTarget) -> Result<()> {
+            self.ensure_owner()?;
+            let mut config = self.ensure_handler(id)?;
+            config.target = target;
+            self.handlers.insert(id, &config);
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn set_handler_enabled(&mut self, id: u32, enabled: bool) -> Result<()> {
+            self.ensure_owner()?;
+            let mut config = self.ensure_handler(id)?;
+            if config.enabled == enabled {
+                return Err(Error::NotChanged);
+            }
+            config.enabled = enabled;
+            if config.enabled {
+                self.active_handlers.push(id);
+            } else {
+                self.active_handlers.retain(|handler| *handler != id);
+            }
+            self.handlers.insert(id, &config);
+            Ok(())
+        }
+
+        /// Called by a scheduler periodically
+        ///
+        /// Polls all the active handlers. A failed handler doesn't stop the others.
+        #[ink(message)]
+        pub fn poll(&self) -> Result<()> {
+            for id in &self.active_handlers {
+                let config = self
+                    .handlers
+                    .get(id)
+                    .expect("Active handler must exist; qed.");
+                match self.poll_handler(&config) {
+                    Ok(Some(tx_id)) => {
+                        pink::info!("[Handler-{id}] submitted: {}", hex::encode(tx_id))
+                    }
+                    Ok(None) => pink::debug!("[Handler-{id}] nothing to submit"),
+                    // Retryable failures are expected to be fixed by the next poll
+                    Err(Error::FailedToSubmitTx(kind)) if kind.is_retryable() => {
+                        pink::info!("[Handler-{id}] will retry: {kind}")
+                    }
+                    Err(e) => pink::warn!("Poll handler {id} failed: {e:?}"),
+                }
+            }
+            Ok(())
+        }
+
+        /// Returns BadOrigin error if the caller is not the owner
+        fn ensure_owner(&self) -> Result<()> {
+            if self.env().caller() == self.owner {
+                Ok(())
+            } else {
+                Err(Error::BadOrigin)
+            }
+        }
+
+        fn ensure_handler(&self, id: u32) -> Result<HandlerConfig> {
+            self.handlers.get(id).ok_or(Error::HandlerNotFound)
+        }
+
+        fn derive_submit_key() -> [u8; 32] {
+            const NONCE: &[u8] = b"submit_key";
+            let private_key = signing::derive_sr25519_key(NONCE);
+            private_key[..32].try_into().expect("Invalid Key Length")
+        }
+
+        /// Gets the rollup result from the handler and submits it
+        ///
+        /// Return the tx id, or `None` if there's nothing to submit.
+        fn poll_handler(&self, config: &HandlerConfig) -> Result<Option<Vec<u8>>> {
+            let handler: RollupHandlerForwarder<pink::PinkEnvironment> =
+                FromAccountId::from_account_id(config.handler);
+            let result = handler
+                .call()
+                .handle_rollup()
+                .try_invoke()
+                .or(Err(Error::FailedToCallHandler))?
+                .or(Err(Error::FailedToCallHandler))?
+                .map_err(Error::HandlerReturnedError)?;
+            match result {
+                Some(result) => self.submit(&config.target, result).map(Some),
+                None => Ok(None),
+            }
+        }
+
+        /// Submits the rollup result to the pinned target of its handler with the submission key
+        ///
+        /// A signed result is relayed to an ink! anchor without the attestor role. Otherwise the
+        /// submission key must be allowed to submit to the anchor.
+        fn submit(&self, pinned: &RollupTarget, mut result: RollupResult) -> Result<Vec<u8>> {
+            if matches!(&result.target, Some(target) if target != pinned) {
+                return Err(Error::TargetMismatch);
+            }
+            result.target = Some(pinned.clone());
+            Ok(result.submit(&self.submit_key)?)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[ink::test]
+        fn handler_management_works() {
+            let _ = env_logger::try_init();
+            pink_extension_runtime::mock_ext::mock_all_ext();
+
+            let accounts = ink::env::test::default_accounts::<pink::PinkEnvironment>();
+            let mut submitter = RollupSubmitter::default();
+            let target = RollupTarget::Ink {
+                rpc: "http://localhost:9944".into(),
+                pallet_id: 7,
+                call_id: 6,
+                contract_id: [1u8; 32],
+            };
+
+            let id = submitter
+                .add_handler(accounts.bob, target.clone())
+                .expect("add handler should succeed");
+            assert_eq!(submitter.get_num_handlers(), 1);
+            assert_eq!(submitter.get_active_handlers(), vec![id]);
+            assert_eq!(submitter.get_handler(id).unwrap().target, target);
+
+            // Only the active handlers are polled
+            assert_eq!(
+                submitter.set_handler_enabled(id, true),
+                Err(Error::NotChanged)
+            );
+            submitter.set_handler_enabled(id, false).unwrap();
+            assert!(submitter.get_active_handlers().is_empty());
+            submitter.poll().expect("poll should succeed");
+
+            // The result must go to the pinned target
+            let result = |target| RollupResult {
+                tx: Default::default(),
+                signature: None,
+                target,
+            };
+            let other = RollupTarget::Evm {
+                rpc: "http://localhost:8545".into(),
+                contract_id: [2u8; 20],
+            };
+            assert_eq!(
+                submitter.submit(&target, result(Some(other.clone()))),
+                Err(Error::TargetMismatch)
+            );
+            submitter.set_handler_target(id, other.clone()).unwrap();
+            assert_eq!(submitter.get_handler(id).unwrap().target, other);
+
+            // Only the owner can manage the handlers
+            ink::env::test::set_caller::<pink::PinkEnvironment>(accounts.bob);
+            assert_eq!(
+                submitter.add_handler(accounts.bob, target.clone()),
+                Err(Error::BadOrigin)
+            );
+            assert_eq!(
+                submitter.set_handler_target(id, target),
+                Err(Error::BadOrigin)
+            );
+            assert_eq!(submitter.set_submit_key(None), Err(Error::BadOrigin));
+        }
+    }
+}
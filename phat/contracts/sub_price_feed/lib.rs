@@ -17,8 +17,12 @@ mod sub_price_feed {
     use pink::ResultExt;
 
     use phat_offchain_rollup::{
-        clients::substrate::{claim_name, get_name_owner, SubstrateRollupClient},
-        Action,
+        clients::{
+            commit_and_submit,
+            substrate::{claim_name, get_name_owner, SubstrateRollupClient},
+            ClientOptions,
+        },
+        Action, ErrorKind,
     };
 
     #[ink(storage)]
@@ -40,6 +44,8 @@ mod sub_price_feed {
         token0: String,
         /// The sedon token in the trading pair
         token1: String,
+        /// The RPC endpoints to fail over to, in order
+        fallback_rpcs: Vec<String>,
     }
 
     #[derive(Encode, Decode, Debug)]
@@ -48,15 +54,19 @@ mod sub_price_feed {
         BadOrigin,
         NotConfigured,
         InvalidKeyLength,
-        FailedToCreateClient,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToCreateClient(ErrorKind),
         FailedToCommitTx,
         FailedToFetchPrice,
-        FailedToGetNameOwner,
-        FailedToClaimName,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToGetNameOwner(ErrorKind),
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToClaimName(ErrorKind),
 
         FailedToGetStorage,
         FailedToCreateTransaction,
-        FailedToSendTransaction,
+        /// The rollup client failed. The kind tells if it's worth retrying.
+        FailedToSendTransaction(ErrorKind),
         FailedToGetBlockHash,
         FailedToDecode,
         RollupAlreadyInitialized,
@@ -97,10 +107,20 @@ mod sub_price_feed {
                 submit_key: submit_key.try_into().or(Err(Error::InvalidKeyLength))?,
                 token0,
                 token1,
+                fallback_rpcs: Default::default(),
             });
             Ok(())
         }
 
+        /// Sets the RPC endpoints to fail over to when the configured one is down (admin only)
+        #[ink(message)]
+        pub fn set_fallback_rpcs(&mut self, rpcs: Vec<String>) -> Result<()> {
+            self.ensure_owner()?;
+            let config = self.config.as_mut().ok_or(Error::NotConfigured)?;
+            config.fallback_rpcs = rpcs;
+            Ok(())
+        }
+
         /// Transfers the ownership of the contract (admin only)
         #[ink(message)]
         pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<()> {
@@ -122,7 +142,7 @@ mod sub_price_feed {
             // Check if the rollup is initialized properly
             let actual_owner = get_name_owner(&config.rpc, &contract_id)
                 .log_err("failed to get name owner")
-                .or(Err(Error::FailedToGetNameOwner))?;
+                .map_err(|e| Error::FailedToGetNameOwner(e.kind()))?;
             if let Some(owner) = actual_owner {
                 let pubkey = pink::ext()
                     .get_public_key(pink::chain_extension::SigType::Sr25519, &config.submit_key);
@@ -141,7 +161,7 @@ mod sub_price_feed {
             )
             .log_err("failed to claim name")
             .map(Some)
-            .or(Err(Error::FailedToClaimName))
+            .map_err(|e| Error::FailedToClaimName(e.kind()))
         }
 
         /// Fetches the price of a trading pair from CoinGecko
@@ -190,10 +210,16 @@ mod sub_price_feed {
             // to read, write, and execute actions on the target chain with atomicity.
             let config = self.ensure_configured()?;
             let contract_id = self.env().account_id();
-            let mut client =
-                SubstrateRollupClient::new(&config.rpc, config.pallet_id, &contract_id, b"q/")
-                    .log_err("failed to create rollup client")
-                    .or(Err(Error::FailedToCreateClient))?;
+            let options = ClientOptions::default().with_fallback_rpcs(&config.fallback_rpcs);
+            let mut client = SubstrateRollupClient::new_with_options(
+                &config.rpc,
+                config.pallet_id,
+                &contract_id,
+                b"q/",
+                &options,
+            )
+            .log_err("failed to create rollup client")
+            .map_err(|e| Error::FailedToCreateClient(e.kind()))?;
 
             // Business logic starts from here.
 
@@ -211,8 +237,8 @@ mod sub_price_feed {
 
             // An offchain rollup contract will get a dedicated kv store on the target blockchain.
             // The kv store can be accessed by the Phat Contract by:
-            // - client.session.get(key)
-            // - client.session.put(key, value)
+            // - client.get(key)
+            // - client.put(key, value)
             //
             // Note that all of the read, write, and custom actions are grouped as a transaction,
             // which is applied on the target blockchain atomically.
@@ -220,18 +246,9 @@ mod sub_price_feed {
             // Business logic ends here.
 
             // Submit the transaction if it's not empty
-            let maybe_submittable = client
-                .commit()
-                .log_err("failed to commit")
-                .or(Err(Error::FailedToCommitTx))?;
-            if let Some(submittable) = maybe_submittable {
-                let tx_id = submittable
-                    .submit(&config.submit_key, 0)
-                    .log_err("failed to submit rollup tx")
-                    .or(Err(Error::FailedToSendTransaction))?;
-                return Ok(Some(tx_id));
-            }
-            Ok(None)
+            commit_and_submit(client, &config.submit_key, None)
+                .log_err("failed to submit rollup tx")
+                .map_err(|e| Error::FailedToSendTransaction(e.kind()))
         }
 
         /// Returns BadOrigin error if the caller is not the owner
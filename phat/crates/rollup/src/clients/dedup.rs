@@ -0,0 +1,105 @@
+//! Suppresses the duplicate submissions of a rollup tx
+//!
+//! A submitted tx is remembered in the local cache of the worker for `DEDUP_TTL_SECS`. Submitting
+//! the same tx to the same anchor again in the window fails with `Error::DuplicateSubmission`
+//! without sending it, because it would only revert on the conditions and waste the fee.
+//!
+//! Only the duplicates sent by the same worker are caught. The cache is local to the worker, so
+//! when the contract runs on several workers, e.g. triggered by the scheduler on each of them, the
+//! duplicates sent by the other workers are only rejected by the conditions of the anchor, and
+//! still cost the fee. Catching them needs a state shared by the workers, which is out of scope.
+//!
+//! The window covers the default drop window of `evm::ReceiptTracker`, and a tx reported dropped
+//! by the tracker is forgotten, so that it can be submitted again right away. Nothing reports a
+//! dropped tx on Substrate and ink!, where it can only be submitted again after the window.
+use crate::{Error, Result};
+
+use alloc::vec::Vec;
+use ink::env::hash::Blake2x256;
+use pink_extension as pink;
+use scale::Encode;
+
+/// How long a submitted tx is remembered, in seconds
+///
+/// It's also the default drop window of `evm::ReceiptTracker`, so the entry of a dropped tx is
+/// still there to forget when the tracker reports it.
+pub const DEDUP_TTL_SECS: u64 = 10 * 60;
+
+const CACHE_PREFIX: &[u8] = b"rollup/submitted/";
+/// The prefix of the cache keys mapping the tx id to the dedup cache key
+const TX_ID_PREFIX: &[u8] = b"rollup/submitted_tx/";
+
+/// The cache key of the submission of `tx` to the anchor `target`
+pub(crate) fn cache_key(target: &[u8], tx: &impl Encode) -> Vec<u8> {
+    let mut hash = [0u8; 32];
+    ink::env::hash_encoded::<Blake2x256, _>(&(target, tx), &mut hash);
+    [CACHE_PREFIX, &hash].concat()
+}
+
+/// Fails with `Error::DuplicateSubmission` if the tx was submitted recently
+pub(crate) fn ensure_not_submitted(key: &[u8]) -> Result<()> {
+    match pink::ext().cache_get(key) {
+        Some(tx_id) => Err(Error::DuplicateSubmission(tx_id)),
+        None => Ok(()),
+    }
+}
+
+/// Remembers the submitted tx with the id returned by the target chain
+pub(crate) fn record_submitted(key: &[u8], tx_id: &[u8]) {
+    // Best effort. The anchor rejects the duplicates anyway.
+    cache_set(key, tx_id);
+    cache_set(&[TX_ID_PREFIX, tx_id].concat(), key);
+}
+
+/// Forgets the submitted tx with the id `tx_id`, e.g. after it's dropped by the target chain
+pub(crate) fn forget_submitted(tx_id: &[u8]) {
+    let id_key = [TX_ID_PREFIX, tx_id].concat();
+    if let Some(key) = pink::ext().cache_get(&id_key) {
+        // The entry may belong to a newer submission of the same tx
+        if pink::ext().cache_get(&key).as_deref() == Some(tx_id) {
+            pink::ext().cache_remove(&key);
+        }
+        pink::ext().cache_remove(&id_key);
+    }
+}
+
+fn cache_set(key: &[u8], value: &[u8]) {
+    if pink::ext().cache_set(key, value).is_ok() {
+        pink::ext().cache_set_expiration(key, DEDUP_TTL_SECS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_works() {
+        pink_extension_runtime::mock_ext::mock_all_ext();
+
+        let key = cache_key(&[1u8; 20], &b"tx".to_vec());
+        assert_ne!(key, cache_key(&[2u8; 20], &b"tx".to_vec()));
+        assert_ne!(key, cache_key(&[1u8; 20], &b"tx2".to_vec()));
+
+        assert!(ensure_not_submitted(&key).is_ok());
+        record_submitted(&key, b"tx_id");
+        assert!(matches!(
+            ensure_not_submitted(&key),
+            Err(Error::DuplicateSubmission(tx_id)) if tx_id == b"tx_id"
+        ));
+
+        // Only the submission with the id is forgotten
+        forget_submitted(b"other_tx_id");
+        assert!(ensure_not_submitted(&key).is_err());
+        forget_submitted(b"tx_id");
+        assert!(ensure_not_submitted(&key).is_ok());
+        // Not after the tx is submitted again with another id
+        record_submitted(&key, b"tx_id");
+        record_submitted(&key, b"tx_id2");
+        forget_submitted(b"tx_id");
+        assert!(matches!(
+            ensure_not_submitted(&key),
+            Err(Error::DuplicateSubmission(tx_id)) if tx_id == b"tx_id2"
+        ));
+    }
+}
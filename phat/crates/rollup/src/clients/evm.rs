@@ -1,13 +1,23 @@
-use crate::{Action, Error, Result, RollupTx};
+use super::{
+    dedup, failover,
+    quorum::{self, Mismatch},
+    split::{self, ActionLog, Chunk, TxLimit},
+    ClientOptions, ReadEndpoint, RollupClient, Submittable, QUEUE_HEAD_KEY,
+};
+use crate::{
+    lock::{self, LockAccess, LockVersion, LockVersionReader},
+    platforms::{Evm, Platform, PlatformCodec},
+    Action, Cond, Error, ErrorKind, Result, RollupResult, RollupTarget, RollupTx,
+};
 
-use alloc::{borrow::ToOwned, vec::Vec};
-use primitive_types::{H160, U256};
-use scale::Encode;
+use alloc::{borrow::ToOwned, boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use primitive_types::{H160, H256, U256};
+use scale::{Decode, Encode};
 
-use ethabi::Token;
+use ethabi::{ParamType, Token};
 use kv_session::{
     rollup,
-    traits::{BumpVersion, KvSnapshot, QueueIndexCodec},
+    traits::{BumpVersion, KvSession, KvSnapshot, QueueIndexCodec, QueueSession},
     RwTracker, Session,
 };
 use pink::ResultExt;
@@ -18,112 +28,469 @@ use pink_web3::{
     keys::pink::KeyPair,
     signing::Key,
     transports::{resolve_ready, PinkHttp},
-    types::{BlockId, BlockNumber, Bytes, U64},
+    types::{BlockId, BlockNumber, Bytes, CallRequest, U64},
 };
 
 const ANCHOR_ABI: &[u8] = include_bytes!("../../res/anchor.abi.json");
 const DEFAULT_QUEUE_PREFIX: &[u8] = b"q/";
 
+/// The [Multicall3](https://github.com/mds1/multicall) contract, deployed at the same address on
+/// most EVM chains
+pub const MULTICALL3_ADDRESS: [u8; 20] = [
+    0xca, 0x11, 0xbd, 0xe0, 0x59, 0x77, 0xb3, 0x63, 0x11, 0x67, 0x02, 0x88, 0x62, 0xbe, 0x2a, 0x17,
+    0x39, 0x76, 0xca, 0x11,
+];
+
+/// The block an `EvmSnapshot` reads the anchor state at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotBlock {
+    /// The latest block. It may be reorged out on some chains.
+    #[default]
+    Latest,
+    /// The latest block with the `safe` tag, on the chains supporting it
+    Safe,
+    /// The latest block with the `finalized` tag, on the chains supporting it
+    Finalized,
+    /// The block `n` blocks behind the latest block
+    Confirmations(u64),
+}
+
+impl SnapshotBlock {
+    /// The block to query. `latest` is called to get the latest block number when needed.
+    fn block_id(self, latest: impl FnOnce() -> Result<u64>) -> Result<BlockId> {
+        let number = match self {
+            SnapshotBlock::Latest => BlockNumber::Latest,
+            SnapshotBlock::Safe => BlockNumber::Safe,
+            SnapshotBlock::Finalized => BlockNumber::Finalized,
+            SnapshotBlock::Confirmations(n) => {
+                BlockNumber::Number(latest()?.saturating_sub(n).into())
+            }
+        };
+        Ok(BlockId::Number(number))
+    }
+}
+
 pub struct EvmSnapshot {
     contract_id: H160,
     contract: Contract<PinkHttp>,
+    eth: Eth<PinkHttp>,
+    endpoint: ReadEndpoint,
     at: u64,
+    /// The hash of the block `at`. The reads are pinned by the hash to fail after a reorg.
+    hash: H256,
+    /// The values read by `prefetch()`
+    prefetched: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// The snapshots of the other endpoints at the same block to cross validate the reads
+    mirrors: Vec<EvmSnapshot>,
+    quorum: usize,
+    mismatch: Mismatch,
 }
 
 impl EvmSnapshot {
     pub fn new(rpc: &str, contract_id: H160) -> Result<Self> {
+        Self::new_with_block(rpc, contract_id, SnapshotBlock::Latest)
+    }
+
+    /// Creates a snapshot at the block selected by `block`
+    pub fn new_with_block(rpc: &str, contract_id: H160, block: SnapshotBlock) -> Result<Self> {
         let eth = Eth::new(PinkHttp::new(rpc));
-        let at: U64 = eth
-            .block_number()
-            .resolve()
-            .log_err("rollup snapshot: failed to get block number")
-            .or(Err(Error::FailedToGetBlockNumber))?;
-        let contract =
-            Contract::from_json(eth, contract_id, ANCHOR_ABI).or(Err(Error::BadEvmAnchorAbi))?;
+        let block = block.block_id(|| {
+            let latest: U64 = eth
+                .block_number()
+                .resolve()
+                .log_err("rollup snapshot: failed to get block number")
+                .or(Err(Error::FailedToGetBlockNumber))?;
+            Ok(latest.as_u64())
+        })?;
+        let (at, hash) = resolve_block(&eth, block)?;
+        Self::pinned(rpc, contract_id, at, hash)
+    }
+
+    /// Creates a snapshot at the block `at`
+    pub fn new_at(rpc: &str, contract_id: H160, at: u64) -> Result<Self> {
+        let eth = Eth::new(PinkHttp::new(rpc));
+        let (at, hash) = resolve_block(&eth, BlockId::Number(BlockNumber::Number(at.into())))?;
+        Self::pinned(rpc, contract_id, at, hash)
+    }
+
+    fn pinned(rpc: &str, contract_id: H160, at: u64, hash: H256) -> Result<Self> {
+        let eth = Eth::new(PinkHttp::new(rpc));
+        let contract = Contract::from_json(eth.clone(), contract_id, ANCHOR_ABI)
+            .or(Err(Error::BadEvmAnchorAbi))?;
         Ok(EvmSnapshot {
             contract,
             contract_id,
-            at: at.0[0],
+            eth,
+            endpoint: ReadEndpoint::new(rpc, &[]),
+            at,
+            hash,
+            prefetched: Default::default(),
+            mirrors: Vec::new(),
+            quorum: 1,
+            mismatch: Default::default(),
         })
     }
+
+    /// Cross validates the reads with the `mirrors` endpoints at the same block
+    ///
+    /// The mirrors are pinned by the block hash, so a mirror on another fork fails to answer.
+    /// A value is accepted if at least `quorum` endpoints, including this one, answered and all
+    /// the answers are equal. See `clients::quorum`.
+    pub fn with_mirrors(mut self, mirrors: &[&str], quorum: usize) -> Result<Self> {
+        quorum::check_quorum(quorum, mirrors.len())?;
+        self.mirrors = mirrors
+            .iter()
+            .map(|rpc| Self::pinned(rpc, self.contract_id, self.at, self.hash))
+            .collect::<Result<_>>()?;
+        self.quorum = quorum;
+        Ok(self)
+    }
+
+    /// Reads from `fallback_rpcs` in order if the endpoint fails before serving any value
+    ///
+    /// The fallback endpoints are pinned by the block hash too. See `ReadEndpoint`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &[String]) -> Self {
+        self.endpoint = ReadEndpoint::new(self.endpoint.current(), fallback_rpcs);
+        self
+    }
+
+    /// Reads the values of `keys` at the snapshot block in a single request
+    ///
+    /// The `getStorage` calls are aggregated by the Multicall3 contract at `multicall`, e.g.
+    /// `MULTICALL3_ADDRESS`. The values are served by `get()` later without hitting the RPC. With
+    /// mirrors, only the values reaching the quorum are kept. See `quorum::validate_prefetched()`.
+    pub fn prefetch(&mut self, keys: &[&[u8]], multicall: H160) -> Result<()> {
+        for mirror in &mut self.mirrors {
+            // The values the mirror failed to prefetch don't count for the quorum
+            let _ = mirror.prefetch(keys, multicall);
+        }
+        let get_storage = self
+            .contract
+            .abi()
+            .function("getStorage")
+            .or(Err(Error::BadEvmAnchorAbi))?;
+        let calls = keys
+            .iter()
+            .map(|key| get_storage.encode_input(&[Token::Bytes(key.to_vec())]))
+            .collect::<core::result::Result<Vec<_>, _>>()
+            .or(Err(Error::BadEvmAnchorAbi))?;
+        let request = CallRequest {
+            to: Some(multicall),
+            data: Some(encode_aggregate3(self.contract_id, calls).into()),
+            ..Default::default()
+        };
+        let output = self
+            .eth
+            .call(request, Some(BlockId::Hash(self.hash)))
+            .resolve()
+            .log_err("rollup snapshot: batch read failed")
+            .map_err(Error::EvmFailedToBatchRead)?;
+
+        let results = decode_aggregate3(&output.0)?;
+        if results.len() != keys.len() {
+            return Err(Error::FailedToDecode);
+        }
+        for (key, result) in keys.iter().zip(results) {
+            // Leave the failed calls to `get()`
+            let Some(data) = result else {
+                continue;
+            };
+            let value = decode_storage(get_storage, &data).ok_or(Error::FailedToDecode)?;
+            self.prefetched.insert(key.to_vec(), value);
+        }
+        if self.mirrors.is_empty() {
+            return Ok(());
+        }
+        let mirrors: Vec<_> = self.mirrors.iter().map(|m| &m.prefetched).collect();
+        quorum::validate_prefetched(&mut self.prefetched, &mirrors, self.quorum, &self.mismatch)
+    }
+
+    /// The flag set if the endpoints disagreed on a read
+    pub(crate) fn mismatch(&self) -> Mismatch {
+        self.mismatch.clone()
+    }
+
     pub fn destruct(self) -> Contract<PinkHttp> {
         self.contract
     }
-}
 
-impl KvSnapshot for EvmSnapshot {
-    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
-        let key: Bytes = key.to_owned().into();
-        let value: Bytes = resolve_ready(self.contract.query(
-            "getStorage",
-            (key.clone(),),
-            self.contract_id,
-            Options::default(),
-            Some(BlockId::Number(BlockNumber::Number(self.at.into()))),
-        ))
-        .log_err("rollup snapshot: get storage failed")
-        .or(Err(kv_session::Error::FailedToGetStorage))?;
+    /// Reads the value from this endpoint only
+    fn read(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.prefetched.get(key) {
+            self.endpoint.served();
+            return Ok(Some(value.clone()));
+        }
+        let get_storage = self
+            .contract
+            .abi()
+            .function("getStorage")
+            .or(Err(kv_session::Error::FailedToDecode))?;
+        let data = get_storage
+            .encode_input(&[Token::Bytes(key.to_vec())])
+            .or(Err(kv_session::Error::FailedToDecode))?;
+        // The fallback endpoints are queried with the same call
+        let output = self.endpoint.read(|rpc| {
+            let request = CallRequest {
+                from: Some(self.contract_id),
+                to: Some(self.contract_id),
+                data: Some(data.clone().into()),
+                ..Default::default()
+            };
+            Eth::new(PinkHttp::new(rpc))
+                .call(request, Some(BlockId::Hash(self.hash)))
+                .resolve()
+                .log_err("rollup snapshot: get storage failed")
+                .or(Err(kv_session::Error::FailedToGetStorage))
+        })?;
+        let value =
+            decode_storage(get_storage, &output.0).ok_or(kv_session::Error::FailedToDecode)?;
 
         #[cfg(feature = "logging")]
-        pink::warn!(
-            "Storage[{}] = {:?}",
-            hex::encode(&key.0),
-            hex::encode(&value.0)
-        );
+        pink::warn!("Storage[{}] = {:?}", hex::encode(key), hex::encode(&value));
+
+        Ok(Some(value))
+    }
+}
+
+/// Decodes the output of `getStorage()`
+fn decode_storage(get_storage: &ethabi::Function, output: &[u8]) -> Option<Vec<u8>> {
+    match get_storage.decode_output(output).ok()?.into_iter().next() {
+        Some(Token::Bytes(value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Gets the number and the hash of `block`
+fn resolve_block(eth: &Eth<PinkHttp>, block: BlockId) -> Result<(u64, H256)> {
+    let block = eth
+        .block(block)
+        .resolve()
+        .log_err("rollup snapshot: failed to get block")
+        .or(Err(Error::FailedToGetBlockHash))?
+        .ok_or(Error::FailedToGetBlockHash)?;
+    match (block.number, block.hash) {
+        (Some(number), Some(hash)) => Ok((number.as_u64(), hash)),
+        _ => Err(Error::FailedToGetBlockHash),
+    }
+}
 
-        Ok(Some(value.0))
+impl KvSnapshot for EvmSnapshot {
+    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+        if self.mirrors.is_empty() {
+            return self.read(key);
+        }
+        quorum::cross_validate(
+            core::iter::once(self)
+                .chain(&self.mirrors)
+                .map(|snapshot| snapshot.read(key)),
+            self.quorum,
+            &self.mismatch,
+        )
     }
 
     fn snapshot_id(&self) -> kv_session::Result<Vec<u8>> {
-        Ok(self.at.encode())
+        Ok(self.hash.encode())
     }
 }
 impl BumpVersion for EvmSnapshot {
     fn bump_version(&self, version: Option<Vec<u8>>) -> kv_session::Result<Vec<u8>> {
-        // u32 is stored in U256 in EVM. Here we parse it as u32, inc, and return in U256 again
-        let old: u32 = match version {
-            Some(v) => RlpCodec::decode(&v)?,
-            None => 0,
-        };
-        let new = old + 1;
-        let mut encoded = [0u8; 32];
-        U256::from(new).to_big_endian(&mut encoded);
-        Ok(encoded.to_vec())
+        Evm::bump_version(version.as_deref()).or(Err(kv_session::Error::FailedToDecode))
     }
 }
 
-pub struct RlpCodec;
-impl QueueIndexCodec for RlpCodec {
-    fn encode(number: u32) -> Vec<u8> {
-        let mut encoded = [0u8; 32];
-        U256::from(number).to_big_endian(&mut encoded);
-        encoded.to_vec()
-    }
-
-    fn decode(raw: impl AsRef<[u8]>) -> kv_session::Result<u32> {
-        // Unlike the decode function for Substrate, EVM contract always returns the raw bytes.
-        // Even if the storage value doesn't exist, it returns a zero length bytes array. So here
-        // we must handle the default value case (`v.len() == 0`).
-        let v = raw.as_ref();
-        if v.len() == 0 {
-            Ok(0)
-        } else if v.len() != 32 {
-            Err(kv_session::Error::FailedToDecode)
-        } else {
-            Ok(U256::from_big_endian(v).low_u32())
-        }
+impl LockVersionReader for EvmSnapshot {
+    fn get_version(&self, key: &[u8]) -> Result<LockVersion> {
+        lock::read_version::<Evm>(self, key)
     }
 }
 
+pub type RlpCodec = PlatformCodec<Evm>;
+
 pub struct EvmRollupClient {
-    actions: Vec<Vec<u8>>,
+    rpc: String,
+    /// The endpoints to fail over to on submission
+    fallback_rpcs: Vec<String>,
+    actions: ActionLog,
+    conds: Vec<Cond>,
+    locks: Vec<LockAccess<Evm>>,
+    tx_limit: TxLimit,
     session: Session<EvmSnapshot, RwTracker, RlpCodec>,
+    mismatch: Mismatch,
 }
 
 pub struct SubmittableRollupTx {
+    rpc: String,
+    fallback_rpcs: Vec<String>,
     contract: Contract<PinkHttp>,
     tx: RollupTx,
-    at: u64,
+    fee: FeeConfig,
+}
+
+/// How an EVM rollup tx is priced
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum GasPricing {
+    /// A legacy tx priced by `eth_gasPrice` of the node
+    #[default]
+    Node,
+    /// A legacy (type 0) tx, for the chains not supporting the typed txs
+    Legacy { gas_price: U256 },
+    /// An EIP-1559 (type 2) tx
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+}
+
+/// The fee settings of an EVM rollup tx
+#[derive(Debug, Clone, Default)]
+pub struct FeeConfig {
+    pub pricing: GasPricing,
+    /// The gas limit in percent of the estimated gas, e.g. 120 for a 20% margin. `None` to use
+    /// the estimated gas as is.
+    pub gas_limit_percent: Option<u32>,
+    /// The max fee in wei to pay for the tx, i.e. gas limit * max price per gas. The tx is not
+    /// submitted if it may cost more.
+    pub spending_cap: Option<U256>,
+    /// The chain id expected from the RPC endpoint. The tx is not submitted to other chains.
+    pub chain_id: Option<u64>,
+}
+
+/// A custom error raised by the EVM anchor, decoded from the revert data of a failed call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnchorError {
+    /// The sender doesn't have the attestor role
+    BadAttestor,
+    BadCondLen {
+        keys: U256,
+        values: U256,
+    },
+    BadUpdateLen {
+        keys: U256,
+        values: U256,
+    },
+    /// A condition is not met. The anchor state changed since the snapshot.
+    CondNotMet {
+        key: Vec<u8>,
+        expected: u32,
+        actual: u32,
+    },
+    CannotDecodeAction(u8),
+    UnsupportedAction(u8),
+    /// A condition or a queue index is not a valid uint32
+    BadUint32(Vec<u8>),
+    /// The queue head to set is not in the queue. It was popped by another session.
+    InvalidPopTarget {
+        target: U256,
+        tail: U256,
+    },
+    /// The meta tx nonce was used by another meta tx
+    NonceTooLow {
+        actual: U256,
+        current: U256,
+    },
+    MetaTxSignatureNotMatch,
+}
+
+impl AnchorError {
+    /// Decodes the revert data, i.e. the error selector followed by the ABI encoded params
+    ///
+    /// Returns `None` if it's not an error of `PhatRollupAnchor` or `MetaTransaction`.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        use ParamType::{Bytes, Uint};
+        if data.len() < 4 {
+            return None;
+        }
+        let (selector, params) = data.split_at(4);
+        let decode = |name: &str, types: &[ParamType]| {
+            if selector != ethabi::short_signature(name, types) {
+                return None;
+            }
+            ethabi::decode(types, params).ok()
+        };
+        let uint_pair = |name: &str| match decode(name, &[Uint(256), Uint(256)])?[..] {
+            [Token::Uint(a), Token::Uint(b)] => Some((a, b)),
+            _ => None,
+        };
+        let uint8 = |name: &str| match decode(name, &[Uint(8)])?[..] {
+            [Token::Uint(id)] => Some(id.low_u32() as u8),
+            _ => None,
+        };
+
+        if decode("BadAttestor", &[]).is_some() {
+            return Some(AnchorError::BadAttestor);
+        }
+        if decode("MetaTxSignatureNotMatch", &[]).is_some() {
+            return Some(AnchorError::MetaTxSignatureNotMatch);
+        }
+        if let Some((keys, values)) = uint_pair("BadCondLen") {
+            return Some(AnchorError::BadCondLen { keys, values });
+        }
+        if let Some((keys, values)) = uint_pair("BadUpdateLen") {
+            return Some(AnchorError::BadUpdateLen { keys, values });
+        }
+        if let Some((target, tail)) = uint_pair("InvalidPopTarget") {
+            return Some(AnchorError::InvalidPopTarget { target, tail });
+        }
+        if let Some((actual, current)) = uint_pair("NonceTooLow") {
+            return Some(AnchorError::NonceTooLow { actual, current });
+        }
+        if let Some(id) = uint8("CannotDecodeAction") {
+            return Some(AnchorError::CannotDecodeAction(id));
+        }
+        if let Some(id) = uint8("UnsupportedAction") {
+            return Some(AnchorError::UnsupportedAction(id));
+        }
+        if let Some(tokens) = decode("CondNotMet", &[Bytes, Uint(32), Uint(32)]) {
+            let [Token::Bytes(key), Token::Uint(expected), Token::Uint(actual)] = &tokens[..]
+            else {
+                return None;
+            };
+            return Some(AnchorError::CondNotMet {
+                key: key.clone(),
+                expected: expected.low_u32(),
+                actual: actual.low_u32(),
+            });
+        }
+        if let Some(tokens) = decode("Internal_toUint32Strict_outOfBounds", &[Bytes]) {
+            let [Token::Bytes(data)] = &tokens[..] else {
+                return None;
+            };
+            return Some(AnchorError::BadUint32(data.clone()));
+        }
+        None
+    }
+
+    /// Decodes the revert data in the `data` field of a JSON-RPC error, a `0x` prefixed hex string
+    pub fn from_revert_data(data: &str) -> Option<Self> {
+        Self::decode(&hex::decode(data.strip_prefix("0x")?).ok()?)
+    }
+
+    /// Finds the anchor error in the error of a failed call
+    ///
+    /// Only the revert data of an `execution reverted` JSON-RPC error is decoded.
+    pub fn find_in(error: &pink_web3::contract::Error) -> Option<Self> {
+        let pink_web3::contract::Error::Api(pink_web3::Error::Rpc(e)) = error else {
+            return None;
+        };
+        Self::from_revert_data(e.data.as_ref()?.as_str()?)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AnchorError::CondNotMet { .. }
+            | AnchorError::InvalidPopTarget { .. }
+            | AnchorError::NonceTooLow { .. } => ErrorKind::Conflict,
+            _ => ErrorKind::Invalid,
+        }
+    }
+}
+
+/// Converts the error of a failed gas estimation, which runs the tx against the latest state
+fn estimate_err(error: pink_web3::contract::Error) -> Error {
+    match AnchorError::find_in(&error) {
+        Some(reverted) => Error::EvmAnchorReverted(reverted),
+        None => Error::EvmFailedToEstimateGas(error),
+    }
 }
 
 impl Action {
@@ -144,25 +511,149 @@ impl Action {
 
 impl EvmRollupClient {
     pub fn new(rpc: &str, contract_id: H160) -> Result<Self> {
-        let kvdb = EvmSnapshot::new(rpc, contract_id)?;
+        Self::new_with_options(rpc, contract_id, &Default::default())
+    }
+
+    /// Creates a client with the failover, the snapshot block, the cross validation and the
+    /// prefetch set by `options`
+    ///
+    /// The prefetch goes through the Multicall3 contract at `MULTICALL3_ADDRESS`. The mirrors are
+    /// pinned by the block hash of the chosen endpoint. See `ClientOptions`.
+    pub fn new_with_options(
+        rpc: &str,
+        contract_id: H160,
+        options: &ClientOptions<SnapshotBlock>,
+    ) -> Result<Self> {
+        failover(rpc, options.fallback_rpcs(), |rpc, fallback_rpcs| {
+            let kvdb = EvmSnapshot::new_with_block(rpc, contract_id, *options.block())?
+                .with_fallback_rpcs(fallback_rpcs);
+            let client = Self::with_snapshot(rpc, kvdb, options)?;
+            // Submit to the endpoints after the chosen one if it fails
+            Ok(EvmRollupClient {
+                fallback_rpcs: fallback_rpcs.to_vec(),
+                ..client
+            })
+        })
+    }
+
+    fn with_snapshot(
+        rpc: &str,
+        mut kvdb: EvmSnapshot,
+        options: &ClientOptions<SnapshotBlock>,
+    ) -> Result<Self> {
+        if let Some((mirrors, quorum)) = options.mirrors() {
+            kvdb = kvdb.with_mirrors(mirrors, quorum)?;
+        }
+        // Multicall3 may not be deployed on the chain, which fails the prefetch harmlessly
+        options.prefetch::<Evm, _>(&mut kvdb, DEFAULT_QUEUE_PREFIX, |kvdb, keys| {
+            kvdb.prefetch(keys, MULTICALL3_ADDRESS.into())
+        })?;
+        let mismatch = kvdb.mismatch();
         let access_tracker = RwTracker::new();
         Ok(Self {
+            rpc: rpc.to_owned(),
+            fallback_rpcs: Vec::new(),
             actions: Default::default(),
+            conds: Default::default(),
+            locks: Default::default(),
+            tx_limit: Default::default(),
             session: Session::new(kvdb, access_tracker, DEFAULT_QUEUE_PREFIX)
                 .map_err(Error::SessionError)?,
+            mismatch,
         })
     }
 
+    /// Splits the rollup tx if its call data exceeds `max_tx_size` bytes
+    ///
+    /// See `RollupClient::commit_split()`. The requests must be popped by `RollupClient::pop()`
+    /// to be tracked.
+    pub fn with_max_tx_size(mut self, max_tx_size: usize) -> Self {
+        self.tx_limit.max_size = Some(max_tx_size);
+        self
+    }
+
+    /// Splits the rollup tx if it has more than `max_tx_actions` actions
+    ///
+    /// It bounds the gas spent by the anchor to handle the actions, which is not covered by the
+    /// size limit. See `with_max_tx_size()`.
+    pub fn with_max_tx_actions(mut self, max_tx_actions: usize) -> Self {
+        self.tx_limit.max_actions = Some(max_tx_actions);
+        self
+    }
+
     pub fn session(&mut self) -> &mut Session<EvmSnapshot, RwTracker, RlpCodec> {
         &mut self.session
     }
 
+    /// Attaches an action to the rollup tx. See `RollupClient::action()`.
     pub fn action(&mut self, action: Action) -> &mut Self {
-        self.actions.push(action.encode_into_evm());
+        RollupClient::action(self, action)
+    }
+
+    /// Commits the session as a rollup tx. See `RollupClient::commit()`.
+    pub fn commit(self) -> Result<Option<SubmittableRollupTx>> {
+        RollupClient::commit(self)
+    }
+
+    fn convert_err(err: kv_session::Error) -> Error {
+        match err {
+            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
+            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+        }
+    }
+}
+
+impl RollupClient for EvmRollupClient {
+    type Submittable = SubmittableRollupTx;
+    type Platform = Evm;
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        self.session
+            .get(key)
+            .map_err(|e| mismatch.or(Self::convert_err(e)))
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        self.session.put(key, value);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.session.delete(key);
+    }
+
+    fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        let request = self
+            .session
+            .pop()
+            .map_err(|e| mismatch.or(Self::convert_err(e)))?;
+        if request.is_some() {
+            self.actions.popped();
+        }
+        Ok(request)
+    }
+
+    fn action(&mut self, action: Action) -> &mut Self {
+        self.actions.push(action);
+        self
+    }
+
+    fn cond(&mut self, cond: Cond) -> &mut Self {
+        self.conds.push(cond);
         self
     }
 
-    pub fn commit(mut self) -> Result<Option<SubmittableRollupTx>> {
+    fn lock(&mut self, access: LockAccess<Self::Platform>) -> &mut Self {
+        self.locks.push(access);
+        self
+    }
+
+    fn commit_split(self) -> Result<Vec<SubmittableRollupTx>> {
+        // The EVM anchor only supports `Eq` on uint32 values
+        if !self.conds.iter().all(|c| matches!(c, Cond::Eq(..))) {
+            return Err(Error::UnsupportedCond);
+        }
         let (session_tx, kvdb) = self.session.commit();
         let raw_tx = rollup::rollup(
             &kvdb,
@@ -171,109 +662,510 @@ impl EvmRollupClient {
                 key_postfix: b":ver".to_vec(),
             },
         )
-        .map_err(Self::convert_err)?;
+        .map_err(|e| self.mismatch.or(Self::convert_err(e)))?;
 
         // #[cfg(feature = "logging")]
         // pink::warn!("RawTx: {raw_tx:?}");
 
-        if let Some(head_idx) = raw_tx.queue_head {
-            self.actions
-                .push(Action::ProcessedTo(head_idx).encode_into_evm());
-        }
-
-        if raw_tx.updates.is_empty() && self.actions.is_empty() {
-            return Ok(None);
+        if raw_tx.updates.is_empty() && self.actions.is_empty() && raw_tx.queue_head.is_none() {
+            return Ok(Vec::new());
         }
 
-        let tx = crate::RollupTx {
+        let mut tx = RollupTx {
             conds: raw_tx
                 .conditions
                 .into_iter()
-                .map(|(k, v)| crate::Cond::Eq(k.into(), v.map(Into::into)))
+                .map(|(k, v)| Cond::Eq(k.into(), v.map(Into::into)))
+                .chain(self.conds)
                 .collect(),
-            actions: self.actions.into_iter().map(Into::into).collect(),
+            actions: Vec::new(),
             updates: raw_tx
                 .updates
                 .into_iter()
                 .map(|(k, v)| (k.into(), v.map(Into::into)))
                 .collect(),
         };
+        for access in &self.locks {
+            access
+                .apply(&mut tx, &kvdb)
+                .map_err(|e| self.mismatch.or(e))?;
+        }
+        tx.normalize()?;
 
-        let at = kvdb.at;
-        Ok(Some(SubmittableRollupTx {
-            contract: kvdb.destruct(),
-            tx,
-            at,
-        }))
+        let chunks = split::split(
+            tx.conds,
+            tx.updates,
+            self.actions,
+            raw_tx.queue_head,
+            self.tx_limit,
+            |head| {
+                let key = [DEFAULT_QUEUE_PREFIX, QUEUE_HEAD_KEY].concat();
+                Cond::Eq(key.into(), Some(RlpCodec::encode(head).into()))
+            },
+            estimate_call_size,
+        );
+
+        let contract = kvdb.destruct();
+        Ok(chunks
+            .into_iter()
+            .map(|chunk| SubmittableRollupTx {
+                rpc: self.rpc.clone(),
+                fallback_rpcs: self.fallback_rpcs.clone(),
+                contract: contract.clone(),
+                tx: RollupTx {
+                    conds: chunk.conds,
+                    actions: chunk
+                        .actions
+                        .into_iter()
+                        .map(|a| a.encode_into_evm().into())
+                        .collect(),
+                    updates: chunk.updates,
+                },
+                fee: Default::default(),
+            })
+            .collect())
     }
+}
 
-    fn convert_err(err: kv_session::Error) -> Error {
-        match err {
-            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
-            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
-        }
+/// Estimates the size of the ABI encoded `rollupU256CondEq` call data
+fn estimate_call_size(chunk: &Chunk) -> usize {
+    // A `bytes[]` argument takes a head slot, a length slot, and then an offset slot, a length
+    // slot and the padded data for each item
+    fn bytes_array(lens: impl Iterator<Item = usize>) -> usize {
+        64 + lens.map(|len| 64 + (len + 31) / 32 * 32).sum::<usize>()
     }
+    let action_len = |action: &Action| match action {
+        Action::Reply(data) => 1 + data.len(),
+        Action::ProcessedTo(_) => 33,
+    };
+    let value_len = |v: &Option<crate::Raw>| v.as_ref().map(|v| v.0.len()).unwrap_or_default();
+    4 + bytes_array(chunk.conds.iter().map(|c| c.key().0.len()))
+        + bytes_array(chunk.conds.iter().map(|c| match c {
+            Cond::Eq(_, v) => value_len(v),
+            _ => 0,
+        }))
+        + bytes_array(chunk.updates.iter().map(|(k, _)| k.0.len()))
+        + bytes_array(chunk.updates.iter().map(|(_, v)| value_len(v)))
+        + bytes_array(chunk.actions.iter().map(action_len))
+}
+
+/// Encodes the call data of Multicall3 `aggregate3()`, calling `target` with each of `calls`
+///
+/// Each call is allowed to fail, so a single bad read doesn't fail the whole batch.
+fn encode_aggregate3(target: H160, calls: Vec<Vec<u8>>) -> Vec<u8> {
+    let calls = calls
+        .into_iter()
+        .map(|data| {
+            Token::Tuple(vec![
+                Token::Address(target),
+                Token::Bool(true),
+                Token::Bytes(data),
+            ])
+        })
+        .collect();
+    let selector = ethabi::short_signature("aggregate3", &[aggregate3_calls_type()]);
+    [&selector[..], &ethabi::encode(&[Token::Array(calls)])].concat()
+}
+
+/// Decodes the output of Multicall3 `aggregate3()`. `None` for the failed calls.
+fn decode_aggregate3(output: &[u8]) -> Result<Vec<Option<Vec<u8>>>> {
+    let result_type = ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])));
+    let mut tokens = ethabi::decode(&[result_type], output).or(Err(Error::FailedToDecode))?;
+    let Some(Token::Array(results)) = tokens.pop() else {
+        return Err(Error::FailedToDecode);
+    };
+    results
+        .into_iter()
+        .map(|result| match result {
+            Token::Tuple(fields) => match &fields[..] {
+                [Token::Bool(true), Token::Bytes(data)] => Ok(Some(data.clone())),
+                [Token::Bool(false), Token::Bytes(_)] => Ok(None),
+                _ => Err(Error::FailedToDecode),
+            },
+            _ => Err(Error::FailedToDecode),
+        })
+        .collect()
+}
+
+fn aggregate3_calls_type() -> ParamType {
+    ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])))
 }
 
 impl SubmittableRollupTx {
-    pub fn submit(self, pair: KeyPair) -> Result<Vec<u8>> {
+    /// Prepares a rollup tx committed elsewhere, e.g. the tx of a `RollupResult`, to submit to
+    /// the anchor at `contract_id`
+    ///
+    /// The actions must be encoded for the EVM anchor, as in `into_rollup_tx()`.
+    pub fn new(rpc: &str, contract_id: H160, tx: RollupTx) -> Result<Self> {
+        let snapshot = EvmSnapshot::new(rpc, contract_id)?;
+        Ok(SubmittableRollupTx {
+            rpc: rpc.to_owned(),
+            fallback_rpcs: Vec::new(),
+            contract: snapshot.destruct(),
+            tx,
+            fee: Default::default(),
+        })
+    }
+
+    /// The RPC endpoint to submit the tx to
+    pub fn rpc(&self) -> &str {
+        &self.rpc
+    }
+
+    /// Submits to `fallback_rpcs` in order if the endpoint fails. See `failover()`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &[String]) -> Self {
+        self.fallback_rpcs = fallback_rpcs.to_vec();
+        self
+    }
+
+    /// Sets the fee settings of the submission. The node decides the price by default.
+    pub fn with_fee(mut self, fee: FeeConfig) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Returns the rollup tx, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_rollup_tx(self) -> RollupTx {
+        self.tx
+    }
+
+    /// Returns the rollup tx with its target, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_result(self) -> RollupResult {
+        RollupResult {
+            target: Some(RollupTarget::Evm {
+                rpc: self.rpc,
+                contract_id: self.contract.address().0,
+            }),
+            tx: self.tx,
+            signature: None,
+        }
+    }
+
+    /// Submits the tx signed by `pair`. Returns the tx hash.
+    ///
+    /// The tx is submitted to the fallback endpoints in order if the endpoint fails.
+    ///
+    /// Fails with `Error::DuplicateSubmission` if the same tx was submitted recently. See
+    /// `clients::dedup`.
+    pub fn submit(mut self, pair: KeyPair) -> Result<Vec<u8>> {
+        let dedup_key = self.dedup_key();
+        dedup::ensure_not_submitted(&dedup_key)?;
+
         // Prepare rollupU256CondEq params
-        let params = self.tx.into_params();
+        let params = core::mem::take(&mut self.tx).into_params()?;
 
-        // Estiamte gas before submission
-        let gas = resolve_ready(
-            self.contract
-                .estimate_gas::<(Token, Token, Token, Token, Token)>(
+        let tx_id = self.send_with_failover(|rpc, contract| {
+            // Estiamte gas before submission
+            let gas = resolve_ready(
+                contract.estimate_gas::<(Token, Token, Token, Token, Token)>(
                     "rollupU256CondEq",
                     params.clone(),
                     pair.address(),
                     Options::default(),
                 ),
-        )
-        .map_err(Error::EvmFailedToEstimateGas)?;
-
-        // Actually submit the tx (no guarantee for success)
-        let tx_id = resolve_ready(self.contract.signed_call(
-            "rollupU256CondEq",
-            params,
-            Options::with(|opt| opt.gas = Some(gas)),
-            pair,
-        ))
-        .map_err(Error::EvmFailedToSubmitTx)?;
+            )
+            .map_err(estimate_err)?;
+            let options = self.tx_options(rpc, gas)?;
+
+            // Actually submit the tx (no guarantee for success)
+            resolve_ready(contract.signed_call("rollupU256CondEq", params.clone(), options, &pair))
+                .map_err(Error::EvmFailedToSubmitTx)
+        })?;
 
         #[cfg(feature = "logging")]
         pink::warn!("Sent = {}", hex::encode(&tx_id));
 
-        Ok(tx_id.encode())
+        let tx_id = tx_id.encode();
+        dedup::record_submitted(&dedup_key, &tx_id);
+        Ok(tx_id)
     }
 
-    pub fn submit_meta_tx(self, pair: &KeyPair, relay_pair: &KeyPair) -> Result<Vec<u8>> {
-        let params = self.tx.into_params();
+    pub fn submit_meta_tx(mut self, pair: &KeyPair, relay_pair: &KeyPair) -> Result<Vec<u8>> {
+        let dedup_key = self.dedup_key();
+        dedup::ensure_not_submitted(&dedup_key)?;
+
+        let params = core::mem::take(&mut self.tx).into_params()?;
         let data = ethabi::encode(&[params.0, params.1, params.2, params.3, params.4]);
-        let meta_params = sign_meta_tx(&self.contract, self.at, &data, pair).unwrap();
-
-        // Estiamte gas before submission
-        let gas = resolve_ready(self.contract.estimate_gas::<(Token, Bytes)>(
-            "metaTxRollupU256CondEq",
-            meta_params.clone(),
-            relay_pair.address(),
-            Options::default(),
-        ))
-        .map_err(Error::EvmFailedToEstimateGas)?;
-
-        // Actually submit the tx (no guarantee for success)
-        let tx_id = resolve_ready(self.contract.signed_call(
-            "metaTxRollupU256CondEq",
-            meta_params,
-            Options::with(|opt| opt.gas = Some(gas)),
-            relay_pair,
-        ))
-        .map_err(Error::EvmFailedToSubmitTx)?;
+
+        // The meta-tx is signed and sent by the same endpoint
+        let tx_id = self.send_with_failover(|rpc, contract| {
+            let meta_params = sign_meta_tx(contract, &data, pair)?;
+
+            // Estiamte gas before submission
+            let gas = resolve_ready(contract.estimate_gas::<(Token, Bytes)>(
+                "metaTxRollupU256CondEq",
+                meta_params.clone(),
+                relay_pair.address(),
+                Options::default(),
+            ))
+            .map_err(estimate_err)?;
+            let options = self.tx_options(rpc, gas)?;
+
+            // Actually submit the tx (no guarantee for success)
+            resolve_ready(contract.signed_call(
+                "metaTxRollupU256CondEq",
+                meta_params,
+                options,
+                relay_pair,
+            ))
+            .map_err(Error::EvmFailedToSubmitTx)
+        })?;
 
         #[cfg(feature = "logging")]
         pink::warn!("Sent = {}", hex::encode(&tx_id));
 
-        Ok(tx_id.encode())
+        let tx_id = tx_id.encode();
+        dedup::record_submitted(&dedup_key, &tx_id);
+        Ok(tx_id)
+    }
+
+    /// Runs `send` with the anchor at the endpoint, or the fallback endpoints in order if it
+    /// fails. See `failover()`.
+    fn send_with_failover<T>(
+        &self,
+        mut send: impl FnMut(&str, &Contract<PinkHttp>) -> Result<T>,
+    ) -> Result<T> {
+        failover(&self.rpc, &self.fallback_rpcs, |rpc, _| {
+            if rpc == self.rpc {
+                return send(rpc, &self.contract);
+            }
+            let eth = Eth::new(PinkHttp::new(rpc));
+            let contract = Contract::from_json(eth, self.contract.address(), ANCHOR_ABI)
+                .or(Err(Error::BadEvmAnchorAbi))?;
+            send(rpc, &contract)
+        })
+    }
+
+    fn dedup_key(&self) -> Vec<u8> {
+        dedup::cache_key(self.contract.address().as_bytes(), &self.tx)
+    }
+
+    /// Builds the tx options with the `estimated_gas` by the fee settings
+    ///
+    /// Fails if `rpc` is on another chain, or the tx may cost more than the spending cap.
+    fn tx_options(&self, rpc: &str, estimated_gas: U256) -> Result<Options> {
+        let fee = &self.fee;
+        let eth = Eth::new(PinkHttp::new(rpc));
+        if let Some(chain_id) = fee.chain_id {
+            let actual = eth
+                .chain_id()
+                .resolve()
+                .map_err(Error::EvmFailedToQueryChain)?;
+            if actual != chain_id.into() {
+                return Err(Error::EvmChainIdMismatch);
+            }
+        }
+        let gas = match fee.gas_limit_percent {
+            Some(percent) => estimated_gas.saturating_mul(percent.into()) / 100,
+            None => estimated_gas,
+        };
+        let mut pricing = fee.pricing.clone();
+        if fee.spending_cap.is_some() && pricing == GasPricing::Node {
+            // Pin the price of the node to check the cost
+            let gas_price = eth
+                .gas_price()
+                .resolve()
+                .map_err(Error::EvmFailedToQueryChain)?;
+            pricing = GasPricing::Legacy { gas_price };
+        }
+        let max_price = match &pricing {
+            GasPricing::Node => None,
+            GasPricing::Legacy { gas_price } => Some(*gas_price),
+            GasPricing::Eip1559 {
+                max_fee_per_gas, ..
+            } => Some(*max_fee_per_gas),
+        };
+        if let (Some(cap), Some(price)) = (fee.spending_cap, max_price) {
+            if gas.saturating_mul(price) > cap {
+                return Err(Error::EvmFeeOverCap);
+            }
+        }
+        Ok(Options::with(|opt| {
+            opt.gas = Some(gas);
+            match pricing {
+                GasPricing::Node => (),
+                GasPricing::Legacy { gas_price } => {
+                    opt.transaction_type = Some(U64::from(0));
+                    opt.gas_price = Some(gas_price);
+                }
+                GasPricing::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                } => {
+                    opt.transaction_type = Some(U64::from(2));
+                    opt.max_fee_per_gas = Some(max_fee_per_gas);
+                    opt.max_priority_fee_per_gas = Some(max_priority_fee_per_gas);
+                }
+            }
+        }))
+    }
+}
+
+impl Submittable for SubmittableRollupTx {
+    fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        SubmittableRollupTx::submit(self, KeyPair::from(*secret_key))
+    }
+
+    fn submit_meta_tx(self, attest_key: &[u8; 32], relay_key: &[u8; 32]) -> Result<Vec<u8>> {
+        SubmittableRollupTx::submit_meta_tx(
+            self,
+            &KeyPair::from(*attest_key),
+            &KeyPair::from(*relay_key),
+        )
+    }
+}
+
+/// The outcome of a submitted EVM tx
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum TxOutcome {
+    /// Not included yet
+    Pending,
+    /// Included and succeeded
+    Mined { block_number: u64 },
+    /// Included but reverted, e.g. a condition is not met
+    Reverted { block_number: u64 },
+    /// Not included in time. It was dropped or replaced.
+    Dropped,
+}
+
+/// Tracks the receipts of the submitted txs across contract invocations
+///
+/// The pending tx hashes of an RPC endpoint are kept in the local cache of the worker. Call
+/// `poll()` periodically, e.g. by the scheduler, to learn the outcomes. A tx is reported only
+/// once, and then forgotten. A dropped tx is also cleared from `clients::dedup`.
+pub struct ReceiptTracker<'a> {
+    rpc: &'a str,
+    fallback_rpcs: &'a [String],
+    drop_after_ms: u64,
+}
+
+/// How long a tx can be pending before it's considered dropped, the same as the dedup window
+pub const DEFAULT_DROP_AFTER_MS: u64 = dedup::DEDUP_TTL_SECS * 1000;
+
+const PENDING_TXS_PREFIX: &[u8] = b"rollup/pending_evm_txs/";
+/// How long the pending txs are kept without being polled, in seconds
+const PENDING_TXS_TTL_SECS: u64 = 24 * 3600;
+
+impl<'a> ReceiptTracker<'a> {
+    pub fn new(rpc: &'a str) -> Self {
+        ReceiptTracker {
+            rpc,
+            fallback_rpcs: &[],
+            drop_after_ms: DEFAULT_DROP_AFTER_MS,
+        }
+    }
+
+    /// Gets the receipts from `fallback_rpcs` in order if the endpoint fails
+    ///
+    /// The txs are still tracked under `rpc`, whichever endpoint they were submitted to.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &'a [String]) -> Self {
+        self.fallback_rpcs = fallback_rpcs;
+        self
+    }
+
+    /// Sets how long a tx can be pending before it's reported as `TxOutcome::Dropped`
+    ///
+    /// Keep it within `dedup::DEDUP_TTL_SECS`. Otherwise the dedup entry of a pending tx may
+    /// expire first, and the tx can be submitted again before it's reported dropped.
+    pub fn with_drop_after_ms(mut self, drop_after_ms: u64) -> Self {
+        self.drop_after_ms = drop_after_ms;
+        self
+    }
+
+    /// Starts to track the tx with the hash `tx_id` returned by the submission
+    pub fn track(&self, tx_id: &[u8]) -> Result<()> {
+        let tx_hash: [u8; 32] = tx_id.try_into().or(Err(Error::FailedToDecode))?;
+        let mut pending = self.load();
+        if pending.iter().all(|(hash, _)| *hash != tx_hash) {
+            pending.push((tx_hash, pink::ext().untrusted_millis_since_unix_epoch()));
+        }
+        self.save(&pending);
+        Ok(())
+    }
+
+    /// Returns the hashes of the txs still pending
+    pub fn pending(&self) -> Vec<[u8; 32]> {
+        self.load().into_iter().map(|(hash, _)| hash).collect()
+    }
+
+    /// Polls the receipts of the pending txs, and returns the outcomes of the resolved ones
+    ///
+    /// The txs failed to get the receipt are left for the next poll.
+    pub fn poll(&self) -> Result<Vec<([u8; 32], TxOutcome)>> {
+        let now = pink::ext().untrusted_millis_since_unix_epoch();
+        let mut resolved = Vec::new();
+        let mut pending = Vec::new();
+        for (tx_hash, submitted_at) in self.load() {
+            let receipt = failover(self.rpc, self.fallback_rpcs, |rpc, _| {
+                Eth::new(PinkHttp::new(rpc))
+                    .transaction_receipt(H256(tx_hash))
+                    .resolve()
+                    .map_err(Error::EvmFailedToQueryChain)
+            });
+            let outcome = match receipt {
+                Ok(Some(receipt)) => {
+                    let block_number = receipt.block_number.unwrap_or_default().as_u64();
+                    if receipt.status == Some(U64::from(1)) {
+                        TxOutcome::Mined { block_number }
+                    } else {
+                        TxOutcome::Reverted { block_number }
+                    }
+                }
+                Ok(None) if now.saturating_sub(submitted_at) > self.drop_after_ms => {
+                    TxOutcome::Dropped
+                }
+                Ok(None) => TxOutcome::Pending,
+                Err(_err) => {
+                    #[cfg(feature = "logging")]
+                    pink::warn!(
+                        "failed to get receipt of {}: {_err:?}",
+                        hex::encode(tx_hash)
+                    );
+                    TxOutcome::Pending
+                }
+            };
+            match outcome {
+                TxOutcome::Pending => pending.push((tx_hash, submitted_at)),
+                outcome => {
+                    if outcome == TxOutcome::Dropped {
+                        // Let the tx be submitted again without waiting for the dedup window
+                        dedup::forget_submitted(&tx_hash);
+                    }
+                    resolved.push((tx_hash, outcome));
+                }
+            }
+        }
+        self.save(&pending);
+        Ok(resolved)
+    }
+
+    fn cache_key(&self) -> Vec<u8> {
+        [PENDING_TXS_PREFIX, self.rpc.as_bytes()].concat()
+    }
+
+    /// Loads the pending txs with their submission time
+    fn load(&self) -> Vec<([u8; 32], u64)> {
+        pink::ext()
+            .cache_get(&self.cache_key())
+            .and_then(|raw| Decode::decode(&mut &raw[..]).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, pending: &[([u8; 32], u64)]) {
+        let key = self.cache_key();
+        if pending.is_empty() {
+            pink::ext().cache_remove(&key);
+        } else if pink::ext().cache_set(&key, &pending.encode()).is_ok() {
+            pink::ext().cache_set_expiration(&key, PENDING_TXS_TTL_SECS);
+        }
     }
 }
 
@@ -282,7 +1174,6 @@ impl SubmittableRollupTx {
 /// Return (ForwardRequest, Sig)
 fn sign_meta_tx(
     contract: &Contract<PinkHttp>,
-    at: u64,
     data: &[u8],
     pair: &KeyPair,
 ) -> Result<(Token, Bytes)> {
@@ -292,11 +1183,12 @@ fn sign_meta_tx(
         (pair.address(), data),
         contract.address(),
         Options::default(),
-        // Currently the strategy is to stick to the snapthost block (`at`). However, it may not
-        // be the best choice depending on the requirement.
-        Some(BlockId::Number(BlockNumber::Number(at.into()))),
+        // The meta-tx nonce must be the next one the forwarder accepts, not the one at the
+        // snapshot block, which falls behind once the signer's meta-txs are included after it.
+        // The pending block also counts the signer's meta-txs still in the pool.
+        Some(BlockId::Number(BlockNumber::Pending)),
     ))
-    .log_err("rollup snapshot: get storage failed")
+    .log_err("meta-tx: read pending nonce failed")
     .map_err(Error::EvmFailedToPrepareMetaTx)?;
     let Token::FixedBytes(hash) = hash else {
         unreachable!()
@@ -327,18 +1219,22 @@ trait IntoRollupParams {
     /// Converts a RollupTx into the EVM contract arguments.
     ///
     /// `(cond_key, cond_values, update_keys, update_values, actions)`
-    fn into_params(self) -> (Token, Token, Token, Token, Token);
+    ///
+    /// Fails if the tx has any condition other than `Cond::Eq`.
+    fn into_params(self) -> Result<(Token, Token, Token, Token, Token)>;
 }
 
 impl IntoRollupParams for RollupTx {
-    fn into_params(self) -> (Token, Token, Token, Token, Token) {
+    fn into_params(self) -> Result<(Token, Token, Token, Token, Token)> {
         let (cond_keys, cond_values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = self
             .conds
             .into_iter()
-            .map(|cond| {
-                let crate::Cond::Eq(k, v) = cond;
-                (k.into(), v.map(Into::into).unwrap_or_default())
+            .map(|cond| match cond {
+                Cond::Eq(k, v) => Ok((k.into(), v.map(Into::into).unwrap_or_default())),
+                _ => Err(Error::UnsupportedCond),
             })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
             .unzip();
         let (update_keys, update_values): (Vec<Vec<u8>>, Vec<Vec<u8>>) = self
             .updates
@@ -346,13 +1242,13 @@ impl IntoRollupParams for RollupTx {
             .map(|(k, v)| (k.into(), v.map(Into::into).unwrap_or_default()))
             .unzip();
         let actions = self.actions.into_iter().map(Into::<Vec<u8>>::into);
-        (
+        Ok((
             Token::Array(cond_keys.into_iter().map(Token::Bytes).collect()),
             Token::Array(cond_values.into_iter().map(Token::Bytes).collect()),
             Token::Array(update_keys.into_iter().map(Token::Bytes).collect()),
             Token::Array(update_values.into_iter().map(Token::Bytes).collect()),
             Token::Array(actions.map(Token::Bytes).collect()),
-        )
+        ))
     }
 }
 
@@ -373,6 +1269,216 @@ mod tests {
         assert_eq!(&der, &hex_literal::hex!("a0b37f8fba683cc68f6574cd43b39f0343a50008bf6ccea9d13231d9e7e2e1e411edc8d307254296264aebfc3dc76cd8b668373a072fd64665b50000e9fcce521c"));
     }
 
+    #[test]
+    fn tx_options_works() {
+        let rpc = "http://localhost:8545";
+        let submittable = |fee| SubmittableRollupTx {
+            rpc: rpc.into(),
+            fallback_rpcs: Vec::new(),
+            contract: Contract::from_json(Eth::new(PinkHttp::new(rpc)), H160::zero(), ANCHOR_ABI)
+                .unwrap(),
+            tx: Default::default(),
+            fee,
+        };
+
+        let options = submittable(Default::default())
+            .tx_options(rpc, 100_000.into())
+            .unwrap();
+        assert_eq!(options.gas, Some(100_000.into()));
+        assert_eq!(options.transaction_type, None);
+
+        let eip1559 = GasPricing::Eip1559 {
+            max_fee_per_gas: 10.into(),
+            max_priority_fee_per_gas: 1.into(),
+        };
+        let options = submittable(FeeConfig {
+            pricing: eip1559.clone(),
+            gas_limit_percent: Some(120),
+            spending_cap: Some(1_200_000.into()),
+            chain_id: None,
+        })
+        .tx_options(rpc, 100_000.into())
+        .unwrap();
+        assert_eq!(options.gas, Some(120_000.into()));
+        assert_eq!(options.transaction_type, Some(2.into()));
+        assert_eq!(options.max_fee_per_gas, Some(10.into()));
+        assert_eq!(options.max_priority_fee_per_gas, Some(1.into()));
+
+        // 120_000 gas * 10 wei exceeds the cap
+        let result = submittable(FeeConfig {
+            pricing: eip1559,
+            gas_limit_percent: Some(120),
+            spending_cap: Some(1_199_999.into()),
+            chain_id: None,
+        })
+        .tx_options(rpc, 100_000.into());
+        assert!(matches!(result, Err(Error::EvmFeeOverCap)));
+
+        let options = submittable(FeeConfig {
+            pricing: GasPricing::Legacy {
+                gas_price: 5.into(),
+            },
+            ..Default::default()
+        })
+        .tx_options(rpc, 100_000.into())
+        .unwrap();
+        assert_eq!(options.transaction_type, Some(0.into()));
+        assert_eq!(options.gas_price, Some(5.into()));
+    }
+
+    #[test]
+    fn receipt_tracker_works() {
+        pink_extension_runtime::mock_ext::mock_all_ext();
+
+        let tracker = ReceiptTracker::new("http://localhost:8545");
+        assert!(tracker.pending().is_empty());
+        assert!(matches!(
+            tracker.track(&[1u8; 31]),
+            Err(Error::FailedToDecode)
+        ));
+        tracker.track(&[1u8; 32]).unwrap();
+        tracker.track(&[2u8; 32]).unwrap();
+        // Tracked only once
+        tracker.track(&[1u8; 32]).unwrap();
+        assert_eq!(tracker.pending(), vec![[1u8; 32], [2u8; 32]]);
+        // The pending txs are per endpoint
+        assert!(ReceiptTracker::new("http://localhost:8546")
+            .pending()
+            .is_empty());
+    }
+
+    #[test]
+    fn snapshot_block_id() {
+        let latest = || Ok(100);
+        assert_eq!(
+            SnapshotBlock::Latest.block_id(latest).unwrap(),
+            BlockId::Number(BlockNumber::Latest)
+        );
+        assert_eq!(
+            SnapshotBlock::Finalized.block_id(latest).unwrap(),
+            BlockId::Number(BlockNumber::Finalized)
+        );
+        assert_eq!(
+            SnapshotBlock::Confirmations(12).block_id(latest).unwrap(),
+            BlockId::Number(BlockNumber::Number(88.into()))
+        );
+        assert_eq!(
+            SnapshotBlock::Confirmations(200).block_id(latest).unwrap(),
+            BlockId::Number(BlockNumber::Number(0.into()))
+        );
+        // The latest block number is only queried when needed
+        assert!(SnapshotBlock::Safe
+            .block_id(|| Err(Error::FailedToGetBlockNumber))
+            .is_ok());
+        assert!(matches!(
+            SnapshotBlock::Confirmations(1).block_id(|| Err(Error::FailedToGetBlockNumber)),
+            Err(Error::FailedToGetBlockNumber)
+        ));
+    }
+
+    #[test]
+    fn anchor_error_decode() {
+        use ParamType::{Bytes, Uint};
+        let revert_data = |name: &str, types: &[ParamType], params: &[Token]| {
+            [
+                &ethabi::short_signature(name, types)[..],
+                &ethabi::encode(params),
+            ]
+            .concat()
+        };
+
+        let data = revert_data(
+            "CondNotMet",
+            &[Bytes, Uint(32), Uint(32)],
+            &[
+                Token::Bytes(b"key".to_vec()),
+                Token::Uint(1.into()),
+                Token::Uint(2.into()),
+            ],
+        );
+        let cond_not_met = AnchorError::CondNotMet {
+            key: b"key".to_vec(),
+            expected: 1,
+            actual: 2,
+        };
+        assert_eq!(AnchorError::decode(&data), Some(cond_not_met.clone()));
+        assert_eq!(cond_not_met.kind(), ErrorKind::Conflict);
+        assert_eq!(
+            AnchorError::decode(&revert_data("BadAttestor", &[], &[])),
+            Some(AnchorError::BadAttestor)
+        );
+        assert_eq!(AnchorError::BadAttestor.kind(), ErrorKind::Invalid);
+        assert_eq!(
+            AnchorError::decode(&revert_data(
+                "InvalidPopTarget",
+                &[Uint(256), Uint(256)],
+                &[Token::Uint(5.into()), Token::Uint(3.into())]
+            )),
+            Some(AnchorError::InvalidPopTarget {
+                target: 5.into(),
+                tail: 3.into()
+            })
+        );
+        // Unknown errors and bad params
+        assert_eq!(AnchorError::decode(&revert_data("Other", &[], &[])), None);
+        assert_eq!(AnchorError::decode(&data[..36]), None);
+
+        // Found in the data of the RPC error
+        let rpc_error = |data: String| {
+            pink_web3::contract::Error::Api(pink_web3::Error::Rpc(pink_web3::rpc::Error {
+                code: pink_web3::rpc::ErrorCode::ServerError(3),
+                message: "execution reverted".into(),
+                data: Some(data.into()),
+            }))
+        };
+        let reverted = rpc_error(alloc::format!("0x{}", hex::encode(&data)));
+        assert_eq!(AnchorError::find_in(&reverted), Some(cond_not_met.clone()));
+        assert_eq!(
+            Error::EvmFailedToEstimateGas(reverted).kind(),
+            ErrorKind::Conflict
+        );
+        // Not the anchor error, or not the revert data
+        assert_eq!(AnchorError::find_in(&rpc_error("0x1234".into())), None);
+        assert_eq!(AnchorError::find_in(&rpc_error(hex::encode(&data))), None);
+        assert_eq!(
+            AnchorError::find_in(&pink_web3::contract::Error::InvalidOutputType("0x".into())),
+            None
+        );
+    }
+
+    #[test]
+    fn aggregate3_codec() {
+        let target: H160 = [1u8; 20].into();
+        let data = encode_aggregate3(target, vec![vec![1, 2, 3], vec![]]);
+        assert_eq!(data[..4], hex_literal::hex!("82ad56cb"));
+        let calls = ethabi::decode(&[aggregate3_calls_type()], &data[4..]).unwrap();
+        assert_eq!(
+            calls,
+            vec![Token::Array(vec![
+                Token::Tuple(vec![
+                    Token::Address(target),
+                    Token::Bool(true),
+                    Token::Bytes(vec![1, 2, 3])
+                ]),
+                Token::Tuple(vec![
+                    Token::Address(target),
+                    Token::Bool(true),
+                    Token::Bytes(vec![])
+                ]),
+            ])]
+        );
+
+        let output = ethabi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![4, 5])]),
+            Token::Tuple(vec![Token::Bool(false), Token::Bytes(vec![])]),
+        ])]);
+        assert_eq!(
+            decode_aggregate3(&output).unwrap(),
+            vec![Some(vec![4, 5]), None]
+        );
+        assert!(decode_aggregate3(&[1, 2, 3]).is_err());
+    }
+
     #[test]
     #[ignore]
     fn meta_tx() {
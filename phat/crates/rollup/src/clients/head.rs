@@ -0,0 +1,96 @@
+//! Selects the block the Substrate and ink! snapshots read at
+//!
+//! The best block may be reorged away, and so may the requests read from it. The replies computed
+//! from them would answer the requests never made. Snapshot at the finalized head, or some blocks
+//! behind the best block, to only act on the settled requests.
+use super::jsonrpc;
+use crate::{Error, Result};
+
+use alloc::format;
+use primitive_types::H256;
+use serde::Deserialize;
+
+use pink::ResultExt;
+use pink_extension as pink;
+
+/// The block a snapshot reads at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotHead {
+    /// The best block. It may be reorged away.
+    #[default]
+    Best,
+    /// The finalized head
+    Finalized,
+    /// The block `n` blocks behind the best block
+    Confirmations(u32),
+}
+
+impl SnapshotHead {
+    /// Gets the hash of the selected block from `rpc`
+    pub fn resolve(self, rpc: &str) -> Result<H256> {
+        match self {
+            SnapshotHead::Best => {
+                subrpc::get_block_hash(rpc, None).or(Err(Error::FailedToGetBlockHash))
+            }
+            SnapshotHead::Finalized => {
+                let resp = jsonrpc::call(rpc, "chain_getFinalizedHead", "")?;
+                parse_hash(&resp)
+            }
+            SnapshotHead::Confirmations(n) => {
+                let resp = jsonrpc::call(rpc, "chain_getHeader", "")?;
+                let number = parse_header_number(&resp)?.saturating_sub(n);
+                let resp = jsonrpc::call(rpc, "chain_getBlockHash", &format!("{number}"))?;
+                parse_hash(&resp)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Header<'a> {
+    number: &'a str,
+}
+
+fn parse_hash(resp: &[u8]) -> Result<H256> {
+    let hash: Option<&str> = jsonrpc::parse(resp)
+        .log_err("rollup snapshot: failed to get block hash")
+        .or(Err(Error::FailedToGetBlockHash))?;
+    let hash = hash.ok_or(Error::FailedToGetBlockHash)?;
+    let hash = jsonrpc::decode_hex(hash).or(Err(Error::FailedToGetBlockHash))?;
+    if hash.len() != 32 {
+        return Err(Error::FailedToGetBlockHash);
+    }
+    Ok(H256::from_slice(&hash))
+}
+
+fn parse_header_number(resp: &[u8]) -> Result<u32> {
+    let header: Option<Header> = jsonrpc::parse(resp)
+        .log_err("rollup snapshot: failed to get header")
+        .or(Err(Error::FailedToGetBlockNumber))?;
+    let number = header.ok_or(Error::FailedToGetBlockNumber)?.number;
+    u32::from_str_radix(number.strip_prefix("0x").unwrap_or(number), 16)
+        .or(Err(Error::FailedToGetBlockNumber))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response() {
+        let hash = br#"{"jsonrpc":"2.0","result":"0x0101010101010101010101010101010101010101010101010101010101010101","id":1}"#;
+        assert_eq!(parse_hash(hash).unwrap(), H256::repeat_byte(1));
+        // Unknown block
+        assert!(matches!(
+            parse_hash(br#"{"jsonrpc":"2.0","result":null,"id":1}"#),
+            Err(Error::FailedToGetBlockHash)
+        ));
+        assert!(matches!(
+            parse_hash(br#"{"jsonrpc":"2.0","result":"0x0101","id":1}"#),
+            Err(Error::FailedToGetBlockHash)
+        ));
+
+        let header = br#"{"jsonrpc":"2.0","result":{"digest":{"logs":[]},"extrinsicsRoot":"0x00","number":"0x1a2b","parentHash":"0x00","stateRoot":"0x00"},"id":1}"#;
+        assert_eq!(parse_header_number(header).unwrap(), 0x1a2b);
+    }
+}
@@ -1,11 +1,9 @@
-use alloc::vec::Vec;
+use alloc::{borrow::ToOwned, collections::BTreeMap, vec::Vec};
 
 use ink::primitives::Hash;
 use kv_session::{
     rollup,
-    traits::{
-        BumpVersion, Key, KvSession, KvSnapshot, QueueIndex, QueueIndexCodec, QueueSession, Value,
-    },
+    traits::{BumpVersion, Key, KvSession, KvSnapshot, QueueIndex, QueueSession, Value},
     RwTracker, Session,
 };
 use pink_extension::chain_extension::signing;
@@ -18,18 +16,38 @@ use primitive_types::H256;
 use scale::{Decode, Encode};
 use subrpc::contracts::*;
 
-pub use crate::{Action, Error, Result};
+use super::{
+    dedup, failover,
+    head::SnapshotHead,
+    quorum::{self, Mismatch},
+    split::{self, ActionLog, Chunk, TxLimit},
+    ClientOptions, ReadEndpoint, RollupClient, Submittable, QUEUE_HEAD_KEY,
+};
+use crate::{
+    lock::{self, LockAccess, LockVersion, LockVersionReader},
+    platforms::{Ink, Platform, PlatformCodec},
+    RollupResult, RollupTarget, RollupTx,
+};
+pub use crate::{Action, Cond, Error, Result};
 
 const DEFAULT_QUEUE_PREFIX: &[u8] = b"q/";
+/// The key of the nonce bumped by the signed rollup txs
+const SIGNED_NONCE_KEY: &[u8] = b"_signed_nonce";
 
 pub type ContractId = [u8; 32];
 
 pub struct InkSnapshot<'a> {
-    rpc: &'a str,
+    endpoint: ReadEndpoint,
     pallet_id: u8,
     call_id: u8,
     contract_id: &'a ContractId,
     at: H256,
+    /// The values read by `prefetch()`, including the missing ones
+    prefetched: BTreeMap<Key, Option<Value>>,
+    /// The snapshots of the other endpoints at the same block to cross validate the reads
+    mirrors: Vec<InkSnapshot<'a>>,
+    quorum: usize,
+    mismatch: Mismatch,
 }
 
 impl<'a> InkSnapshot<'a> {
@@ -39,40 +57,152 @@ impl<'a> InkSnapshot<'a> {
         call_id: u8,
         contract_id: &'a ContractId,
     ) -> Result<Self> {
-        let hash = subrpc::get_block_hash(rpc, None).or(Err(Error::FailedToGetBlockHash))?;
-        Ok(InkSnapshot {
-            rpc,
+        Self::new_with_head(rpc, pallet_id, call_id, contract_id, SnapshotHead::Best)
+    }
+
+    /// Creates a snapshot at the block selected by `head`
+    pub fn new_with_head(
+        rpc: &'a str,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: &'a ContractId,
+        head: SnapshotHead,
+    ) -> Result<Self> {
+        let hash = head.resolve(rpc)?;
+        Ok(Self::new_at(rpc, pallet_id, call_id, contract_id, hash))
+    }
+
+    /// Creates a snapshot at the block `at`
+    pub fn new_at(
+        rpc: &'a str,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: &'a ContractId,
+        at: H256,
+    ) -> Self {
+        InkSnapshot {
+            endpoint: ReadEndpoint::new(rpc, &[]),
             pallet_id,
             call_id,
             contract_id,
-            at: hash,
-        })
+            at,
+            prefetched: Default::default(),
+            mirrors: Vec::new(),
+            quorum: 1,
+            mismatch: Default::default(),
+        }
     }
-}
 
-impl<'a> KvSnapshot for InkSnapshot<'a> {
-    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Value>> {
-        let contract = InkContract::new(self.rpc, self.pallet_id, self.call_id, self.contract_id);
+    /// Cross validates the reads with the `mirrors` endpoints at the same block
+    ///
+    /// A value is accepted if at least `quorum` endpoints, including this one, answered and all
+    /// the answers are equal. See `clients::quorum`.
+    pub fn with_mirrors(mut self, mirrors: &[&'a str], quorum: usize) -> Result<Self> {
+        quorum::check_quorum(quorum, mirrors.len())?;
+        let (pallet_id, call_id, contract_id, at) =
+            (self.pallet_id, self.call_id, self.contract_id, self.at);
+        self.mirrors = mirrors
+            .iter()
+            .map(|&rpc| Self::new_at(rpc, pallet_id, call_id, contract_id, at))
+            .collect();
+        self.quorum = quorum;
+        Ok(self)
+    }
 
-        // result of the query
-        type QueryResult = Option<Vec<u8>>;
-        // call the method
-        let value: QueryResult = contract
+    /// Reads from `fallback_rpcs` in order if the endpoint fails before serving any value
+    ///
+    /// The fallback endpoints read at the same block. See `ReadEndpoint`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &[String]) -> Self {
+        self.endpoint = ReadEndpoint::new(self.endpoint.current(), fallback_rpcs);
+        self
+    }
+
+    /// Reads the values of `keys` at the snapshot block with a single `RollupAnchor::get_values`
+    /// query
+    ///
+    /// The values are served by `get()` later without hitting the RPC. With mirrors, only the
+    /// values reaching the quorum are kept. See `quorum::validate_prefetched()`.
+    pub fn prefetch(&mut self, keys: &[&[u8]]) -> Result<()> {
+        for mirror in &mut self.mirrors {
+            // The values the mirror failed to prefetch don't count for the quorum
+            let _ = mirror.prefetch(keys);
+        }
+        let contract = InkContract::new(
+            self.endpoint.current(),
+            self.pallet_id,
+            self.call_id,
+            self.contract_id,
+        );
+        let keys: Vec<Key> = keys.iter().map(|key| key.to_vec()).collect();
+        let values: Vec<Option<Value>> = contract
             .query_at(
                 *self.contract_id,
-                ink::selector_bytes!("RollupAnchor::get_value"),
-                Some(&key),
+                ink::selector_bytes!("RollupAnchor::get_values"),
+                Some(&keys),
                 0,
                 Some(self.at),
             )
-            .log_err("Rollup snapshot: failed to get storage")
-            .map_err(|_| kv_session::Error::FailedToGetStorage)?;
+            .log_err("Rollup snapshot: failed to get values")
+            .map_err(Error::InkFailedToQueryContract)?;
+        if values.len() != keys.len() {
+            return Err(Error::FailedToDecodeStorage);
+        }
+        self.prefetched.extend(keys.into_iter().zip(values));
+        if self.mirrors.is_empty() {
+            return Ok(());
+        }
+        let mirrors: Vec<_> = self.mirrors.iter().map(|m| &m.prefetched).collect();
+        quorum::validate_prefetched(&mut self.prefetched, &mirrors, self.quorum, &self.mismatch)
+    }
+
+    /// The flag set if the endpoints disagreed on a read
+    pub(crate) fn mismatch(&self) -> Mismatch {
+        self.mismatch.clone()
+    }
+
+    /// Reads the value from this endpoint only
+    fn read(&self, key: &[u8]) -> kv_session::Result<Option<Value>> {
+        if let Some(value) = self.prefetched.get(key) {
+            self.endpoint.served();
+            return Ok(value.clone());
+        }
+        // result of the query
+        type QueryResult = Option<Vec<u8>>;
+        let value: QueryResult = self.endpoint.read(|rpc| {
+            let contract = InkContract::new(rpc, self.pallet_id, self.call_id, self.contract_id);
+            // call the method
+            contract
+                .query_at(
+                    *self.contract_id,
+                    ink::selector_bytes!("RollupAnchor::get_value"),
+                    Some(&key),
+                    0,
+                    Some(self.at),
+                )
+                .log_err("Rollup snapshot: failed to get storage")
+                .map_err(|_| kv_session::Error::FailedToGetStorage)
+        })?;
 
         #[cfg(feature = "logging")]
         debug!("Snapshot - key: {:02x?} - value: {:02x?}", &key, &value);
 
         Ok(value)
     }
+}
+
+impl<'a> KvSnapshot for InkSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Value>> {
+        if self.mirrors.is_empty() {
+            return self.read(key);
+        }
+        quorum::cross_validate(
+            core::iter::once(self)
+                .chain(&self.mirrors)
+                .map(|snapshot| snapshot.read(key)),
+            self.quorum,
+            &self.mismatch,
+        )
+    }
 
     fn snapshot_id(&self) -> kv_session::Result<Vec<u8>> {
         Ok(self.at.encode())
@@ -81,41 +211,36 @@ impl<'a> KvSnapshot for InkSnapshot<'a> {
 
 impl<'a> BumpVersion for InkSnapshot<'a> {
     fn bump_version(&self, version: Option<Vec<u8>>) -> kv_session::Result<Vec<u8>> {
-        match version {
-            Some(v) => {
-                let ver = u32::decode(&mut &v[..]).or(Err(kv_session::Error::FailedToDecode))?;
-                Ok((ver + 1).encode())
-            }
-            None => Ok(1u32.encode()),
-        }
+        Ink::bump_version(version.as_deref()).or(Err(kv_session::Error::FailedToDecode))
     }
 }
 
-pub struct ScaleCodec;
-impl QueueIndexCodec for ScaleCodec {
-    fn encode(number: QueueIndex) -> Vec<u8> {
-        number.encode()
-    }
-
-    fn decode(raw: impl AsRef<[u8]>) -> kv_session::Result<QueueIndex> {
-        // QueueIndex is stored as a value in the rollup kv store. When the value is empty, it's
-        // treated as the default value (0 for u32). However, this function only handles the
-        // non-empty case (empty value != zero length bytes). So here, `[]` is not considered.
-        QueueIndex::decode(&mut raw.as_ref()).or(Err(kv_session::Error::FailedToDecode))
+impl<'a> LockVersionReader for InkSnapshot<'a> {
+    fn get_version(&self, key: &[u8]) -> Result<LockVersion> {
+        lock::read_version::<Ink>(self, key)
     }
 }
 
+pub type ScaleCodec = PlatformCodec<Ink>;
+
 pub struct InkRollupClient<'a> {
     rpc: &'a str,
+    /// The endpoints to fail over to on submission
+    fallback_rpcs: &'a [String],
     pallet_id: u8,
     call_id: u8,
     contract_id: &'a ContractId,
-    actions: Vec<Action>,
+    actions: ActionLog,
+    conds: Vec<Cond>,
+    locks: Vec<LockAccess<Ink>>,
+    tx_limit: TxLimit,
     session: Session<InkSnapshot<'a>, RwTracker, ScaleCodec>,
+    mismatch: Mismatch,
 }
 
 pub struct SubmittableRollupTx<'a> {
     rpc: &'a str,
+    fallback_rpcs: &'a [String],
     pallet_id: u8,
     call_id: u8,
     contract_id: &'a ContractId,
@@ -125,7 +250,7 @@ pub struct SubmittableRollupTx<'a> {
 #[derive(Debug, Default, PartialEq, Eq, Encode, Decode)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub struct InkRollupTx {
-    conditions: Vec<(Key, Option<Value>)>,
+    conditions: Vec<Condition>,
     updates: Vec<(Key, Option<Value>)>,
     actions: Vec<Action>,
 }
@@ -139,6 +264,61 @@ pub enum HandleActionInput {
     RevokeAttestor(ContractId),
 }
 
+/// The condition checked by `RollupAnchor::rollup_cond`
+#[derive(Debug, PartialEq, Eq, Encode, Decode, Clone)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum Condition {
+    Eq(Key, Option<Value>),
+    Ne(Key, Option<Value>),
+    Exists(Key),
+    NotExists(Key),
+    Le(Key, u32),
+    Ge(Key, u32),
+}
+
+impl From<Cond> for Condition {
+    fn from(cond: Cond) -> Self {
+        match cond {
+            Cond::Eq(k, v) => Condition::Eq(k.into(), v.map(Into::into)),
+            Cond::Ne(k, v) => Condition::Ne(k.into(), v.map(Into::into)),
+            Cond::Exists(k) => Condition::Exists(k.into()),
+            Cond::NotExists(k) => Condition::NotExists(k.into()),
+            Cond::Le(k, n) => Condition::Le(k.into(), n),
+            Cond::Ge(k, n) => Condition::Ge(k.into(), n),
+        }
+    }
+}
+
+impl From<Condition> for Cond {
+    fn from(cond: Condition) -> Self {
+        match cond {
+            Condition::Eq(k, v) => Cond::Eq(k.into(), v.map(Into::into)),
+            Condition::Ne(k, v) => Cond::Ne(k.into(), v.map(Into::into)),
+            Condition::Exists(k) => Cond::Exists(k.into()),
+            Condition::NotExists(k) => Cond::NotExists(k.into()),
+            Condition::Le(k, n) => Cond::Le(k.into(), n),
+            Condition::Ge(k, n) => Cond::Ge(k.into(), n),
+        }
+    }
+}
+
+/// Converts to the tx signed for `RollupAnchor::rollup_signed`
+///
+/// The actions are SCALE encoded, which is the encoding of `HandleActionInput` on the anchor.
+impl From<InkRollupTx> for RollupTx {
+    fn from(tx: InkRollupTx) -> Self {
+        RollupTx {
+            conds: tx.conditions.into_iter().map(Into::into).collect(),
+            actions: tx.actions.iter().map(|a| a.encode().into()).collect(),
+            updates: tx
+                .updates
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.map(Into::into)))
+                .collect(),
+        }
+    }
+}
+
 impl Action {
     fn encode_into_ink(self) -> HandleActionInput {
         match self {
@@ -155,21 +335,89 @@ impl<'a> InkRollupClient<'a> {
         call_id: u8,
         contract_id: &'a ContractId,
     ) -> Result<Self> {
-        let kvdb = InkSnapshot::new(rpc, pallet_id, call_id, contract_id)?;
+        Self::new_with_options(rpc, pallet_id, call_id, contract_id, &Default::default())
+    }
+
+    /// Creates a client with the failover, the snapshot block, the cross validation and the
+    /// prefetch set by `options`
+    ///
+    /// The anchor must implement `RollupAnchor::get_values` to prefetch. See `ClientOptions` and
+    /// `clients::head`.
+    pub fn new_with_options(
+        rpc: &'a str,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: &'a ContractId,
+        options: &ClientOptions<'a, SnapshotHead>,
+    ) -> Result<Self> {
+        failover(rpc, options.fallback_rpcs(), |rpc, fallback_rpcs| {
+            let kvdb =
+                InkSnapshot::new_with_head(rpc, pallet_id, call_id, contract_id, *options.block())?
+                    .with_fallback_rpcs(fallback_rpcs);
+            let client = Self::with_snapshot(rpc, pallet_id, call_id, contract_id, kvdb, options)?;
+            // Submit to the endpoints after the chosen one if it fails
+            Ok(InkRollupClient {
+                fallback_rpcs,
+                ..client
+            })
+        })
+    }
+
+    fn with_snapshot(
+        rpc: &'a str,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: &'a ContractId,
+        mut kvdb: InkSnapshot<'a>,
+        options: &ClientOptions<'a, SnapshotHead>,
+    ) -> Result<Self> {
+        if let Some((mirrors, quorum)) = options.mirrors() {
+            kvdb = kvdb.with_mirrors(mirrors, quorum)?;
+        }
+        options.prefetch::<Ink, _>(&mut kvdb, DEFAULT_QUEUE_PREFIX, |kvdb, keys| {
+            kvdb.prefetch(keys)
+        })?;
+        let mismatch = kvdb.mismatch();
         let access_tracker = RwTracker::new();
         Ok(InkRollupClient {
             rpc,
+            fallback_rpcs: &[],
             pallet_id,
             call_id,
             contract_id,
             actions: Default::default(),
+            conds: Default::default(),
+            locks: Default::default(),
+            tx_limit: Default::default(),
             session: Session::new(kvdb, access_tracker, DEFAULT_QUEUE_PREFIX)
                 .map_err(Error::SessionError)?,
+            mismatch,
         })
     }
 
+    /// Splits the rollup tx if its encoded call exceeds `max_tx_size` bytes
+    ///
+    /// See `RollupClient::commit_split()`. The requests must be popped by `pop()` to be tracked.
+    pub fn with_max_tx_size(mut self, max_tx_size: usize) -> Self {
+        self.tx_limit.max_size = Some(max_tx_size);
+        self
+    }
+
+    /// Splits the rollup tx if it has more than `max_tx_actions` actions
+    ///
+    /// It bounds the weight spent by the anchor to handle the actions, which is not covered by
+    /// the size limit. See `with_max_tx_size()`.
+    pub fn with_max_tx_actions(mut self, max_tx_actions: usize) -> Self {
+        self.tx_limit.max_actions = Some(max_tx_actions);
+        self
+    }
+
     pub fn get<K: scale::Encode, V: scale::Decode>(&mut self, key: &K) -> Result<Option<V>> {
-        let v = self.session.get(&key.encode())?;
+        let mismatch = &self.mismatch;
+        let v = self
+            .session
+            .get(&key.encode())
+            .map_err(|e| mismatch.or(e.into()))?;
 
         if let Some(v) = v {
             let v = V::decode(&mut v.as_slice())?;
@@ -188,7 +436,7 @@ impl<'a> InkRollupClient<'a> {
     }
 
     pub fn pop<V: scale::Codec>(&mut self) -> Result<Option<V>> {
-        let v = self.session.pop().map_err(Self::convert_err)?;
+        let v = RollupClient::pop(self)?;
 
         if let Some(v) = v {
             let v = V::decode(&mut v.as_slice())?;
@@ -198,12 +446,97 @@ impl<'a> InkRollupClient<'a> {
         Ok(None)
     }
 
+    /// Pops up to `n` requests and decodes them. See `RollupClient::pop_many()`.
+    pub fn pop_many<V: scale::Codec>(&mut self, n: usize) -> Result<Vec<V>> {
+        RollupClient::pop_many(self, n)?
+            .iter()
+            .map(|v| Ok(V::decode(&mut v.as_slice())?))
+            .collect()
+    }
+
+    /// Attaches an action to the rollup tx. See `RollupClient::action()`.
     pub fn action(&mut self, action: Action) -> &mut Self {
+        RollupClient::action(self, action)
+    }
+
+    /// Commits the session as a rollup tx. See `RollupClient::commit()`.
+    pub fn commit(self) -> Result<Option<SubmittableRollupTx<'a>>> {
+        RollupClient::commit(self)
+    }
+
+    /// Commits the session as a `RollupResult` signed by the attestor
+    ///
+    /// Any relayer can submit the result by `RollupAnchor::rollup_signed`. The tx bumps a nonce
+    /// in the anchor kv store and is conditioned on its current value, so it can only be applied
+    /// once. Only the first tx is returned if the session is split.
+    pub fn commit_signed(mut self, attestor_key: &[u8; 32]) -> Result<Option<RollupResult>> {
+        let nonce = RollupClient::get(&mut self, SIGNED_NONCE_KEY)?;
+        let nonce = Ink::bump_version(nonce.as_deref())?;
+        RollupClient::put(&mut self, SIGNED_NONCE_KEY, nonce);
+
+        let Some(submittable) = RollupClient::commit(self)? else {
+            return Ok(None);
+        };
+        let mut result = submittable.into_result();
+        result.sign(attestor_key)?;
+        Ok(Some(result))
+    }
+
+    fn convert_err(err: kv_session::Error) -> Error {
+        match err {
+            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
+            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+        }
+    }
+}
+
+impl<'a> RollupClient for InkRollupClient<'a> {
+    type Submittable = SubmittableRollupTx<'a>;
+    type Platform = Ink;
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        self.session
+            .get(key)
+            .map_err(|e| mismatch.or(Self::convert_err(e)))
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        self.session.put(key, value);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.session.delete(key);
+    }
+
+    fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        let request = self
+            .session
+            .pop()
+            .map_err(|e| mismatch.or(Self::convert_err(e)))?;
+        if request.is_some() {
+            self.actions.popped();
+        }
+        Ok(request)
+    }
+
+    fn action(&mut self, action: Action) -> &mut Self {
         self.actions.push(action);
         self
     }
 
-    pub fn commit(mut self) -> Result<Option<SubmittableRollupTx<'a>>> {
+    fn cond(&mut self, cond: Cond) -> &mut Self {
+        self.conds.push(cond);
+        self
+    }
+
+    fn lock(&mut self, access: LockAccess<Self::Platform>) -> &mut Self {
+        self.locks.push(access);
+        self
+    }
+
+    fn commit_split(self) -> Result<Vec<SubmittableRollupTx<'a>>> {
         let (session_tx, kvdb) = self.session.commit();
         let raw_tx = rollup::rollup(
             &kvdb,
@@ -212,62 +545,178 @@ impl<'a> InkRollupClient<'a> {
                 key_postfix: b":ver".to_vec(),
             },
         )
-        .map_err(Self::convert_err)?;
+        .map_err(|e| self.mismatch.or(Self::convert_err(e)))?;
 
-        if let Some(head_idx) = raw_tx.queue_head {
-            self.actions.push(Action::ProcessedTo(head_idx));
+        if raw_tx.updates.is_empty() && self.actions.is_empty() && raw_tx.queue_head.is_none() {
+            return Ok(Vec::new());
         }
 
-        if raw_tx.updates.is_empty() && self.actions.is_empty() {
-            return Ok(None);
+        let mut tx = RollupTx {
+            conds: raw_tx
+                .conditions
+                .into_iter()
+                .map(|(k, v)| Cond::Eq(k.into(), v.map(Into::into)))
+                .chain(self.conds)
+                .collect(),
+            actions: Vec::new(),
+            updates: raw_tx
+                .updates
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.map(Into::into)))
+                .collect(),
+        };
+        for access in &self.locks {
+            access
+                .apply(&mut tx, &kvdb)
+                .map_err(|e| self.mismatch.or(e))?;
         }
+        tx.normalize()?;
+
+        let chunks = split::split(
+            tx.conds,
+            tx.updates,
+            self.actions,
+            raw_tx.queue_head,
+            self.tx_limit,
+            |head| {
+                let key = [DEFAULT_QUEUE_PREFIX, QUEUE_HEAD_KEY].concat();
+                Cond::Eq(key.into(), Some(head.encode().into()))
+            },
+            estimate_call_size,
+        );
 
-        let tx = InkRollupTx {
-            conditions: raw_tx.conditions,
-            updates: raw_tx.updates,
-            actions: self.actions,
-        };
+        Ok(chunks
+            .into_iter()
+            .map(|chunk| SubmittableRollupTx {
+                rpc: self.rpc,
+                fallback_rpcs: self.fallback_rpcs,
+                pallet_id: self.pallet_id,
+                call_id: self.call_id,
+                contract_id: self.contract_id,
+                tx: InkRollupTx {
+                    conditions: chunk.conds.into_iter().map(Into::into).collect(),
+                    updates: chunk
+                        .updates
+                        .into_iter()
+                        .map(|(k, v)| (k.into(), v.map(Into::into)))
+                        .collect(),
+                    actions: chunk.actions,
+                },
+            })
+            .collect())
+    }
+}
 
-        Ok(Some(SubmittableRollupTx {
-            rpc: self.rpc,
-            pallet_id: self.pallet_id,
-            call_id: self.call_id,
-            contract_id: self.contract_id,
-            tx,
-        }))
+/// Estimates the size of the SCALE encoded `rollup_cond` call
+///
+/// `Cond` and `Action` have the same encoding as `Condition` and `HandleActionInput`.
+fn estimate_call_size(chunk: &Chunk) -> usize {
+    4 + (&chunk.conds, &chunk.updates, &chunk.actions).encoded_size()
+}
+
+impl<'a> SubmittableRollupTx<'a> {
+    /// Prepares a rollup tx committed elsewhere, e.g. the tx of a `RollupResult`, to submit to
+    /// the ink! anchor
+    ///
+    /// The actions must be SCALE encoded `Action`s, as in `into_rollup_tx()`.
+    pub fn new(
+        rpc: &'a str,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: &'a ContractId,
+        tx: RollupTx,
+    ) -> Result<Self> {
+        let actions = tx
+            .actions
+            .into_iter()
+            .map(|action| Action::decode(&mut &action.0[..]))
+            .collect::<core::result::Result<_, _>>()?;
+        Ok(SubmittableRollupTx {
+            rpc,
+            fallback_rpcs: &[],
+            pallet_id,
+            call_id,
+            contract_id,
+            tx: InkRollupTx {
+                conditions: tx.conds.into_iter().map(Into::into).collect(),
+                updates: tx
+                    .updates
+                    .into_iter()
+                    .map(|(k, v)| (k.into(), v.map(Into::into)))
+                    .collect(),
+                actions,
+            },
+        })
     }
 
-    fn convert_err(err: kv_session::Error) -> Error {
-        match err {
-            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
-            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+    /// Submits to `fallback_rpcs` in order if the endpoint fails. See `failover()`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &'a [String]) -> Self {
+        self.fallback_rpcs = fallback_rpcs;
+        self
+    }
+
+    /// Returns the rollup tx, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_rollup_tx(self) -> RollupTx {
+        self.tx.into()
+    }
+
+    /// Returns the rollup tx with its target, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_result(self) -> RollupResult {
+        RollupResult {
+            target: Some(RollupTarget::Ink {
+                rpc: self.rpc.to_owned(),
+                pallet_id: self.pallet_id,
+                call_id: self.call_id,
+                contract_id: *self.contract_id,
+            }),
+            tx: self.tx.into(),
+            signature: None,
         }
     }
-}
 
-impl<'a> SubmittableRollupTx<'a> {
+    /// Submits the tx with `secret_key`, which must be an attestor of the anchor
+    ///
+    /// The tx is submitted to the fallback endpoints in order if the endpoint fails.
+    ///
+    /// Fails with `Error::DuplicateSubmission` if the same tx was submitted recently. See
+    /// `clients::dedup`.
     pub fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
-        let params = self.tx.into_params();
+        let dedup_key = self.dedup_key();
+        dedup::ensure_not_submitted(&dedup_key)?;
 
-        let contract = InkContract::new(self.rpc, self.pallet_id, self.call_id, self.contract_id);
+        let params = self.tx.into_params();
 
-        let result = contract
-            .dry_run_and_send_transaction(
-                ink::selector_bytes!("RollupAnchor::rollup_cond_eq"),
-                Some(&params),
-                0,
-                secret_key,
-            )
+        let result = failover(self.rpc, self.fallback_rpcs, |rpc, _| {
+            let contract = InkContract::new(rpc, self.pallet_id, self.call_id, self.contract_id);
+            match &params {
+                RollupParams::CondEq(params) => contract.dry_run_and_send_transaction(
+                    ink::selector_bytes!("RollupAnchor::rollup_cond_eq"),
+                    Some(params),
+                    0,
+                    secret_key,
+                ),
+                RollupParams::Cond(params) => contract.dry_run_and_send_transaction(
+                    ink::selector_bytes!("RollupAnchor::rollup_cond"),
+                    Some(params),
+                    0,
+                    secret_key,
+                ),
+            }
             .log_err("dry run and send transaction failed")
-            .map_err(Error::InkFailedToCallContract)?;
+            .map_err(Error::InkFailedToCallContract)
+        })?;
 
         #[cfg(feature = "logging")]
         debug!("Sent = {}", hex::encode(&result));
 
+        dedup::record_submitted(&dedup_key, &result);
         Ok(result)
     }
 
     pub fn submit_meta_tx(self, attestor_key: &[u8; 32], relay_key: &[u8; 32]) -> Result<Vec<u8>> {
+        let dedup_key = self.dedup_key();
+        dedup::ensure_not_submitted(&dedup_key)?;
+
         let params = self.tx.into_params();
 
         let public_key: [u8; 33] = signing::get_public_key(attestor_key, signing::SigType::Ecdsa)
@@ -276,67 +725,131 @@ impl<'a> SubmittableRollupTx<'a> {
 
         let origin: [u8; 32] = get_ecdsa_account_id(&public_key);
 
-        let meta_params = (origin, params.encode());
+        let (encoded_params, selector) = match params {
+            RollupParams::CondEq(params) => (
+                params.encode(),
+                ink::selector_bytes!("MetaTransaction::meta_tx_rollup_cond_eq"),
+            ),
+            RollupParams::Cond(params) => (
+                params.encode(),
+                ink::selector_bytes!("MetaTransaction::meta_tx_rollup_cond"),
+            ),
+        };
 
         #[cfg(feature = "logging")]
         {
             debug!("query method prepare");
             debug!("origin: {:?}", &origin);
-            debug!("encoded params {:02x?}", params.encode());
+            debug!("encoded params {:02x?}", &encoded_params);
         }
 
-        let contract = InkContract::new(self.rpc, self.pallet_id, self.call_id, self.contract_id);
+        let meta_params = (origin, encoded_params);
+
+        // The meta-tx is prepared and sent by the same endpoint
+        let result = failover(self.rpc, self.fallback_rpcs, |rpc, _| {
+            let contract = InkContract::new(rpc, self.pallet_id, self.call_id, self.contract_id);
+
+            // result of the query
+            type PrepareResult = core::result::Result<(ForwardRequest, Hash), ContractError>;
+            // call the method
+            let result: PrepareResult = contract
+                .query(
+                    origin,
+                    ink::selector_bytes!("MetaTransaction::prepare"),
+                    Some(&meta_params),
+                    0,
+                )
+                .log_err("dry run and send transaction failed")
+                .map_err(Error::InkFailedToQueryContract)?;
+
+            let (forward_request, hash) = result.map_err(|_| Error::InkFailedToPrepareMetaTx)?;
+
+            #[cfg(feature = "logging")]
+            {
+                debug!("forwardRequest: {:02x?}", &forward_request);
+                debug!("hash: {:02x?}", &hash);
+            }
 
-        // result of the query
-        type PrepareResult = core::result::Result<(ForwardRequest, Hash), ContractError>;
-        // call the method
-        let result: PrepareResult = contract
-            .query(
-                origin,
-                ink::selector_bytes!("MetaTransaction::prepare"),
-                Some(&meta_params),
-                0,
-            )
-            .log_err("dry run and send transaction failed")
-            .map_err(Error::InkFailedToQueryContract)?;
+            // the attestor sign the hash
+            //let signature = signing::sign(hash.as_ref(), attestor_key, signing::SigType::Ecdsa);
+            let message: [u8; 32] = hash
+                .as_ref()
+                .to_vec()
+                .try_into()
+                .expect("Hash should be of length 32");
+            let signature = signing::ecdsa_sign_prehashed(attestor_key, message);
 
-        let (forward_request, hash) = result.map_err(|_| Error::InkFailedToPrepareMetaTx)?;
+            #[cfg(feature = "logging")]
+            debug!("signature: {:02x?}", signature);
 
-        #[cfg(feature = "logging")]
-        {
-            debug!("forwardRequest: {:02x?}", &forward_request);
-            debug!("hash: {:02x?}", &hash);
-        }
+            let params = (forward_request, signature);
 
-        // the attestor sign the hash
-        //let signature = signing::sign(hash.as_ref(), attestor_key, signing::SigType::Ecdsa);
-        let message: [u8; 32] = hash
-            .as_ref()
-            .to_vec()
-            .try_into()
-            .expect("Hash should be of length 32");
-        let signature = signing::ecdsa_sign_prehashed(attestor_key, message);
+            contract
+                .dry_run_and_send_transaction(selector, Some(&params), 0, relay_key)
+                .log_err("dry run and send transaction failed")
+                .map_err(Error::InkFailedToCallContract)
+        })?;
 
         #[cfg(feature = "logging")]
-        debug!("signature: {:02x?}", signature);
+        debug!("Sent = {}", hex::encode(&result));
 
-        let params = (forward_request, signature);
+        dedup::record_submitted(&dedup_key, &result);
+        Ok(result)
+    }
 
-        let result = contract
-            .dry_run_and_send_transaction(
-                ink::selector_bytes!("MetaTransaction::meta_tx_rollup_cond_eq"),
-                Some(&params),
-                0,
-                relay_key,
-            )
-            .log_err("dry run and send transaction failed")
-            .map_err(Error::InkFailedToCallContract)?;
+    /// Relays the tx signed by the attestor with `signature` to `RollupAnchor::rollup_signed`
+    ///
+    /// `relay_key` pays the fee and doesn't need to be an attestor. See `RollupResult::sign()`.
+    pub fn submit_signed(self, signature: [u8; 65], relay_key: &[u8; 32]) -> Result<Vec<u8>> {
+        let dedup_key = self.dedup_key();
+        dedup::ensure_not_submitted(&dedup_key)?;
+
+        let tx: RollupTx = self.tx.into();
+        let params = (
+            tx.conds
+                .into_iter()
+                .map(Condition::from)
+                .collect::<Vec<_>>(),
+            tx.actions.into_iter().map(Vec::from).collect::<Vec<_>>(),
+            tx.updates
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.map(Into::into)))
+                .collect::<Vec<(Key, Option<Value>)>>(),
+            signature,
+        );
+
+        let result = failover(self.rpc, self.fallback_rpcs, |rpc, _| {
+            InkContract::new(rpc, self.pallet_id, self.call_id, self.contract_id)
+                .dry_run_and_send_transaction(
+                    ink::selector_bytes!("RollupAnchor::rollup_signed"),
+                    Some(&params),
+                    0,
+                    relay_key,
+                )
+                .log_err("dry run and send transaction failed")
+                .map_err(Error::InkFailedToCallContract)
+        })?;
 
         #[cfg(feature = "logging")]
         debug!("Sent = {}", hex::encode(&result));
 
+        dedup::record_submitted(&dedup_key, &result);
         Ok(result)
     }
+
+    fn dedup_key(&self) -> Vec<u8> {
+        dedup::cache_key(self.contract_id, &self.tx)
+    }
+}
+
+impl<'a> Submittable for SubmittableRollupTx<'a> {
+    fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        SubmittableRollupTx::submit(self, secret_key)
+    }
+
+    fn submit_meta_tx(self, attest_key: &[u8; 32], relay_key: &[u8; 32]) -> Result<Vec<u8>> {
+        SubmittableRollupTx::submit_meta_tx(self, attest_key, relay_key)
+    }
 }
 
 /// Converts a compressed ECDSA public key to AccountId
@@ -358,25 +871,39 @@ struct ForwardRequest {
     data: Vec<u8>,
 }
 
-type RollupParamsType = (
+type RollupCondEqParamsType = (
+    Vec<(Vec<u8>, Option<Vec<u8>>)>,
     Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    Vec<HandleActionInput>,
+);
+
+type RollupCondParamsType = (
+    Vec<Condition>,
     Vec<(Vec<u8>, Option<Vec<u8>>)>,
     Vec<HandleActionInput>,
 );
 
+/// The arguments of `rollup_cond_eq` or `rollup_cond`
+enum RollupParams {
+    CondEq(RollupCondEqParamsType),
+    Cond(RollupCondParamsType),
+}
+
 trait IntoRollupParams {
     /// Converts a RollupTx into the Ink contract arguments.
-    fn into_params(self) -> RollupParamsType;
+    ///
+    /// Prefers `rollup_cond_eq` if all the conditions are `Eq` to stay compatible with the
+    /// anchors without `rollup_cond`.
+    fn into_params(self) -> RollupParams;
 }
 
 impl IntoRollupParams for InkRollupTx {
-    fn into_params(self) -> RollupParamsType {
+    fn into_params(self) -> RollupParams {
         #[cfg(feature = "logging")]
         {
             debug!("conditions ------");
-            self.conditions.clone().into_iter().for_each(|(k, v)| {
-                debug!("k: {:02x?}", &k);
-                debug!("v: {:02x?}", &v);
+            self.conditions.iter().for_each(|c| {
+                debug!("c: {:02x?}", c);
             });
 
             debug!("updates ------");
@@ -404,7 +931,23 @@ impl IntoRollupParams for InkRollupTx {
                 }
             })
             .collect();
-        (self.conditions, self.updates, actions)
+        if self
+            .conditions
+            .iter()
+            .all(|c| matches!(c, Condition::Eq(..)))
+        {
+            let conditions = self
+                .conditions
+                .into_iter()
+                .filter_map(|c| match c {
+                    Condition::Eq(k, v) => Some((k, v)),
+                    _ => None,
+                })
+                .collect();
+            RollupParams::CondEq((conditions, self.updates, actions))
+        } else {
+            RollupParams::Cond((self.conditions, self.updates, actions))
+        }
     }
 }
 
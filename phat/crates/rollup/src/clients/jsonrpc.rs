@@ -0,0 +1,207 @@
+//! The raw JSON-RPC requests to the Substrate nodes
+//!
+//! subrpc only tells if a request failed, while the error object of the node tells why, e.g. an
+//! outdated nonce or a low balance. The requests whose errors matter are sent from here.
+//!
+//! The independent calls of the same method are sent in a JSON-RPC batch by `batch()`, to save
+//! the round trips.
+use crate::{Error, Result};
+
+use alloc::{format, string::String, vec, vec::Vec};
+use serde::Deserialize;
+
+use pink::ResultExt;
+use pink_extension as pink;
+
+#[derive(Deserialize)]
+pub(crate) struct Response<'a, T> {
+    pub result: Option<T>,
+    #[serde(borrow)]
+    pub error: Option<ErrorObject<'a>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ErrorObject<'a> {
+    pub code: i64,
+    pub message: &'a str,
+    pub data: Option<&'a str>,
+}
+
+impl From<ErrorObject<'_>> for Error {
+    fn from(error: ErrorObject<'_>) -> Self {
+        Error::RpcError {
+            code: error.code,
+            message: error.message.into(),
+            data: error.data.map(Into::into),
+        }
+    }
+}
+
+/// Sends a JSON-RPC request and returns the response body
+///
+/// `params` is the JSON of the params without the brackets.
+pub(crate) fn call(rpc: &str, method: &str, params: &str) -> Result<Vec<u8>> {
+    post(rpc, request(1, method, params))
+}
+
+/// Sends the JSON-RPC calls of `method` with each of `params` in a single batch request, and
+/// returns the response body
+///
+/// Each `params` is as in `call()`. The response is parsed by `parse_batch()`.
+pub(crate) fn batch(rpc: &str, method: &str, params: &[&str]) -> Result<Vec<u8>> {
+    let requests: Vec<String> = params
+        .iter()
+        .enumerate()
+        .map(|(id, params)| request(id, method, params))
+        .collect();
+    post(rpc, format!("[{}]", requests.join(",")))
+}
+
+fn request(id: usize, method: &str, params: &str) -> String {
+    format!(r#"{{"id":{id},"jsonrpc":"2.0","method":"{method}","params":[{params}]}}"#)
+}
+
+fn post(rpc: &str, data: String) -> Result<Vec<u8>> {
+    let headers = vec![("Content-Type".into(), "application/json".into())];
+    let resp = pink::http_post!(rpc, data.into_bytes(), headers);
+    if resp.status_code != 200 {
+        return Err(Error::RpcNetworkError);
+    }
+    Ok(resp.body)
+}
+
+/// Parses the result of a JSON-RPC response
+///
+/// Returns `Error::RpcError` if the node answered with an error, and `Error::FailedToDecode` if
+/// the response is malformed.
+pub(crate) fn parse<'de, T: Deserialize<'de>>(resp: &'de [u8]) -> Result<Option<T>> {
+    let parsed: Response<T> = pink_json::from_slice(resp)
+        .log_err("rollup rpc: failed to parse response")
+        .or(Err(Error::FailedToDecode))?;
+    match parsed.error {
+        Some(error) => Err(error.into()),
+        None => Ok(parsed.result),
+    }
+}
+
+#[derive(Deserialize)]
+struct BatchResponse<'a, T> {
+    id: usize,
+    result: Option<T>,
+    #[serde(borrow)]
+    error: Option<ErrorObject<'a>>,
+}
+
+/// Parses the results of a batch of `n` calls sent by `batch()`, in the order of the calls
+///
+/// The node may answer the calls in any order, so they are matched by the ids. Returns
+/// `Error::RpcError` if the node answered any call, or the whole batch, with an error.
+pub(crate) fn parse_batch<'de, T: Deserialize<'de>>(
+    resp: &'de [u8],
+    n: usize,
+) -> Result<Vec<Option<T>>> {
+    let parsed: core::result::Result<Vec<BatchResponse<T>>, _> = pink_json::from_slice(resp);
+    let Ok(parsed) = parsed else {
+        // A node rejecting the whole batch answers with a single error object
+        parse::<&str>(resp)?;
+        return Err(Error::FailedToDecode);
+    };
+    let mut results: Vec<Option<Option<T>>> = (0..n).map(|_| None).collect();
+    for response in parsed {
+        if let Some(error) = response.error {
+            return Err(error.into());
+        }
+        match results.get_mut(response.id) {
+            Some(slot @ None) => *slot = Some(response.result),
+            _ => return Err(Error::FailedToDecode),
+        }
+    }
+    results
+        .into_iter()
+        .map(|result| result.ok_or(Error::FailedToDecode))
+        .collect()
+}
+
+/// Submits a signed extrinsic. Returns the tx hash.
+pub(crate) fn submit_extrinsic(rpc: &str, signed_tx: &[u8]) -> Result<Vec<u8>> {
+    let resp = call(
+        rpc,
+        "author_submitExtrinsic",
+        &format!("\"0x{}\"", hex::encode(signed_tx)),
+    )?;
+    let tx_hash: String = parse(&resp)?.ok_or(Error::FailedToSendTransaction)?;
+    decode_hex(&tx_hash).or(Err(Error::FailedToSendTransaction))
+}
+
+/// Decodes a hex string with an optional `0x` prefix
+pub(crate) fn decode_hex(s: &str) -> core::result::Result<Vec<u8>, hex::FromHexError> {
+    hex::decode(s.strip_prefix("0x").unwrap_or(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorKind;
+
+    #[test]
+    fn parse_error_object() {
+        let ok = br#"{"jsonrpc":"2.0","result":"0x0102","id":1}"#;
+        assert_eq!(parse::<&str>(ok).unwrap(), Some("0x0102"));
+
+        let outdated = br#"{"jsonrpc":"2.0","error":{"code":1010,"message":"Invalid Transaction","data":"Transaction is outdated"},"id":1}"#;
+        let err = parse::<&str>(outdated).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::RpcError { code: 1010, data: Some(data), .. } if data == "Transaction is outdated"
+        ));
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+
+        assert!(matches!(
+            parse::<&str>(b"<html>bad gateway</html>"),
+            Err(Error::FailedToDecode)
+        ));
+    }
+
+    #[test]
+    fn parse_batch_response() {
+        // Answered out of order
+        let resp = br#"[{"jsonrpc":"2.0","result":null,"id":1},
+            {"jsonrpc":"2.0","result":"0x01","id":0}]"#;
+        assert_eq!(
+            parse_batch::<&str>(resp, 2).unwrap(),
+            vec![Some("0x01"), None]
+        );
+        // A missing, duplicated or unknown id
+        assert!(matches!(
+            parse_batch::<&str>(resp, 3),
+            Err(Error::FailedToDecode)
+        ));
+        assert!(matches!(
+            parse_batch::<&str>(resp, 1),
+            Err(Error::FailedToDecode)
+        ));
+        let duplicated = br#"[{"jsonrpc":"2.0","result":"0x01","id":0},
+            {"jsonrpc":"2.0","result":"0x02","id":0}]"#;
+        assert!(matches!(
+            parse_batch::<&str>(duplicated, 2),
+            Err(Error::FailedToDecode)
+        ));
+        // A call or the whole batch is rejected
+        let failed = br#"[{"jsonrpc":"2.0","result":"0x01","id":0},
+            {"jsonrpc":"2.0","error":{"code":-32602,"message":"Invalid params"},"id":1}]"#;
+        assert!(matches!(
+            parse_batch::<&str>(failed, 2),
+            Err(Error::RpcError { code: -32602, .. })
+        ));
+        let rejected =
+            br#"{"jsonrpc":"2.0","error":{"code":-32600,"message":"Invalid request"},"id":null}"#;
+        assert!(matches!(
+            parse_batch::<&str>(rejected, 2),
+            Err(Error::RpcError { code: -32600, .. })
+        ));
+        assert!(matches!(
+            parse_batch::<&str>(b"<html>bad gateway</html>", 2),
+            Err(Error::FailedToDecode)
+        ));
+    }
+}
@@ -1,3 +1,11 @@
+use crate::{
+    lock::LockAccess, platforms::Platform, Action, Cond, Error, ErrorKind, Result, RollupResult,
+    RollupTarget,
+};
+use alloc::{string::String, vec, vec::Vec};
+use core::cell::Cell;
+use kv_session::traits::KvSnapshot;
+
 #[cfg(feature = "evm")]
 pub mod evm;
 
@@ -6,3 +14,588 @@ pub mod substrate;
 
 #[cfg(feature = "ink")]
 pub mod ink;
+
+#[cfg(feature = "pink-extension")]
+pub mod dedup;
+#[cfg(any(feature = "substrate", feature = "ink"))]
+pub mod head;
+#[cfg(any(feature = "substrate", feature = "ink"))]
+pub(crate) mod jsonrpc;
+pub(crate) mod quorum;
+pub(crate) mod split;
+
+/// The keys of the queue head and tail, under the queue prefix
+pub(crate) const QUEUE_HEAD_KEY: &[u8] = b"_head";
+pub(crate) const QUEUE_TAIL_KEY: &[u8] = b"_tail";
+
+/// The common interface of the rollup clients
+///
+/// It covers the kv store, the request queue, and the rollup actions of a rollup session, so the
+/// business logic of a Phat Contract can be written once and targeted at any supported chain.
+/// Keys and values are the raw bytes stored in the anchor. Encoding them is up to the caller.
+pub trait RollupClient {
+    /// The committed rollup tx ready to submit to the target chain
+    type Submittable: Submittable;
+    /// The platform of the anchor, which decides the encoding of the values and the locks
+    type Platform: Platform;
+
+    /// Reads a value from the rollup kv store
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Writes a value to the rollup kv store
+    fn put(&mut self, key: &[u8], value: Vec<u8>);
+    /// Removes a value from the rollup kv store
+    fn delete(&mut self, key: &[u8]);
+    /// Pops the next request from the rollup queue
+    fn pop(&mut self) -> Result<Option<Vec<u8>>>;
+    /// Pops up to `n` requests from the rollup queue, in the queue order
+    ///
+    /// On the anchors with a request queue (EVM, ink! and the simulator), all the requests popped
+    /// in a session are marked processed by a single `ProcessedTo` in the rollup tx, so a batch of
+    /// requests can be answered by one tx. The Substrate client attaches no `ProcessedTo`, since
+    /// the pallet anchor has no request queue. Note that a split tx is only cut where a request
+    /// is popped, so pop and answer the requests one by one with `pop()` if the replies may
+    /// exceed the tx size limit.
+    fn pop_many(&mut self, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut requests = Vec::new();
+        while requests.len() < n {
+            match self.pop()? {
+                Some(request) => requests.push(request),
+                None => break,
+            }
+        }
+        Ok(requests)
+    }
+    /// Attaches an action to the rollup tx
+    fn action(&mut self, action: Action) -> &mut Self;
+    /// Attaches an extra condition to the rollup tx
+    ///
+    /// The conditions of the values read in the session are added automatically. Use this to
+    /// express the constraints not covered by the reads, e.g. "only create if missing". Not all
+    /// the anchors support all the conditions.
+    fn cond(&mut self, cond: Cond) -> &mut Self;
+    /// Acquires a lock in the rollup tx
+    ///
+    /// The lock versions are read from the snapshot of the session on commit. The tx is rejected
+    /// by the anchor if any of the locks is written by another tx in between. See `lock::Locks`.
+    fn lock(&mut self, access: LockAccess<Self::Platform>) -> &mut Self;
+    /// Commits the session as a sequence of rollup txs
+    ///
+    /// If the client has a tx limit (e.g. `EvmRollupClient::with_max_tx_size()`), an oversized
+    /// rollup tx is split at the request boundaries. The limits only approximate the gas or the
+    /// weight of the tx. Each tx processes a part of the popped requests, and only applies after
+    /// the previous one, so they must be submitted in order. The requests not covered by the
+    /// submitted txs stay in the queue, and will be popped again by the next session. A session
+    /// with kv updates or locks is never split, since its updates can't be attributed to the
+    /// requests. See `split`.
+    ///
+    /// Return an empty list if there's nothing to submit.
+    fn commit_split(self) -> Result<Vec<Self::Submittable>>;
+    /// Commits the session as a rollup tx
+    ///
+    /// Only the first tx is returned if the session is split, and the truncation is logged. The
+    /// requests answered by the dropped txs stay in the queue, and are answered again by the next
+    /// session. It's safe since a split session never has kv updates. Use `commit_split()` to submit them all. Return `None` if there's nothing to
+    /// submit.
+    fn commit(self) -> Result<Option<Self::Submittable>>
+    where
+        Self: Sized,
+    {
+        let mut txs = self.commit_split()?.into_iter();
+        let first = txs.next();
+        #[cfg(feature = "logging")]
+        {
+            let dropped = txs.count();
+            if dropped > 0 {
+                pink_extension::warn!(
+                    "rollup commit: {dropped} split txs left to the next session"
+                );
+            }
+        }
+        Ok(first)
+    }
+}
+
+/// A committed rollup tx that can be submitted to the target chain
+pub trait Submittable {
+    /// Signs the tx with `secret_key` and submits it. Returns the tx hash.
+    fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>>;
+    /// Signs the tx with `attest_key`, and submits it as a meta-tx paid by `relay_key`. Returns
+    /// the tx hash.
+    ///
+    /// Fails with `Error::UnsupportedMetaTx` if the anchor doesn't accept meta-txs.
+    fn submit_meta_tx(self, attest_key: &[u8; 32], relay_key: &[u8; 32]) -> Result<Vec<u8>>
+    where
+        Self: Sized,
+    {
+        let _ = (attest_key, relay_key);
+        Err(Error::UnsupportedMetaTx)
+    }
+}
+
+/// Commits the session of `client`, and submits the rollup tx if there's anything to submit
+///
+/// The tx is signed with `attest_key`. If `sender_key` is given, the tx is sent as a meta-tx paid
+/// by `sender_key`. Returns the tx hash, or `None` if there's nothing to submit.
+pub fn commit_and_submit<C: RollupClient>(
+    client: C,
+    attest_key: &[u8; 32],
+    sender_key: Option<&[u8; 32]>,
+) -> Result<Option<Vec<u8>>> {
+    let Some(submittable) = client.commit()? else {
+        return Ok(None);
+    };
+    let tx_id = match sender_key {
+        Some(sender_key) => submittable.submit_meta_tx(attest_key, sender_key)?,
+        None => submittable.submit(attest_key)?,
+    };
+    Ok(Some(tx_id))
+}
+
+/// The options of a rollup client, applied when the client starts its session
+///
+/// `B` selects the block the snapshot reads at, `evm::SnapshotBlock` for the EVM client, and
+/// `head::SnapshotHead` for the Substrate and ink! clients. The options compose, e.g. the
+/// prefetched values are cross validated by the mirrors of the endpoint chosen by the failover.
+#[derive(Debug, Clone)]
+pub struct ClientOptions<'a, B> {
+    fallback_rpcs: &'a [String],
+    block: B,
+    mirrors: &'a [&'a str],
+    quorum: usize,
+    /// The keys and the number of the queued requests to prefetch
+    prefetch: Option<(&'a [&'a [u8]], usize)>,
+}
+
+impl<B: Default> Default for ClientOptions<'_, B> {
+    fn default() -> Self {
+        ClientOptions {
+            fallback_rpcs: &[],
+            block: Default::default(),
+            mirrors: &[],
+            quorum: 1,
+            prefetch: None,
+        }
+    }
+}
+
+impl<'a, B> ClientOptions<'a, B> {
+    /// Falls over to `fallback_rpcs` in order if the endpoint fails
+    ///
+    /// The client is created with the first endpoint that works. It keeps reading from that
+    /// endpoint, unless the reads fail before any value is served, and the committed tx is
+    /// submitted to the fallback endpoints too if that endpoint fails. See `failover()`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &'a [String]) -> Self {
+        self.fallback_rpcs = fallback_rpcs;
+        self
+    }
+
+    /// Reads the anchor state at the block selected by `block`
+    ///
+    /// Choose the finalized block or some confirmations on the reorg-prone chains, so that the
+    /// session never acts on the state reorged out later.
+    pub fn with_block(mut self, block: B) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Cross validates the reads with the `mirrors` endpoints at the same block
+    ///
+    /// A value is accepted if at least `quorum` endpoints, including the chosen one, answered
+    /// and all the answers are equal. See `clients::quorum`.
+    pub fn with_mirrors(mut self, mirrors: &'a [&'a str], quorum: usize) -> Self {
+        self.mirrors = mirrors;
+        self.quorum = quorum;
+        self
+    }
+
+    /// Reads the values of `keys` and the queue head and tail in a single request, and then up
+    /// to `requests` queued requests from the head in a second one
+    ///
+    /// It's only an optimization. The keys are read one by one later if the batch read fails.
+    pub fn with_prefetch(mut self, keys: &'a [&'a [u8]], requests: usize) -> Self {
+        self.prefetch = Some((keys, requests));
+        self
+    }
+
+    pub(crate) fn block(&self) -> &B {
+        &self.block
+    }
+
+    pub(crate) fn fallback_rpcs(&self) -> &'a [String] {
+        self.fallback_rpcs
+    }
+
+    pub(crate) fn mirrors(&self) -> Option<(&'a [&'a str], usize)> {
+        (!self.mirrors.is_empty()).then_some((self.mirrors, self.quorum))
+    }
+
+    /// Prefetches the keys and the queued requests into `kvdb` by `batch_read`
+    ///
+    /// The requests are located by the queue head and tail read in the first batch. The failed
+    /// reads are ignored, leaving the keys to be read one by one later. Only fails if the
+    /// endpoints disagree.
+    pub(crate) fn prefetch<P: Platform, S: KvSnapshot>(
+        &self,
+        kvdb: &mut S,
+        queue_prefix: &[u8],
+        mut batch_read: impl FnMut(&mut S, &[&[u8]]) -> Result<()>,
+    ) -> Result<()> {
+        let mut batch_read = |kvdb: &mut S, keys: &[&[u8]]| match batch_read(kvdb, keys) {
+            Err(Error::RpcMismatch) => Err(Error::RpcMismatch),
+            result => Ok(result.is_ok()),
+        };
+        let Some((keys, requests)) = self.prefetch else {
+            return Ok(());
+        };
+        let head_key = [queue_prefix, QUEUE_HEAD_KEY].concat();
+        let tail_key = [queue_prefix, QUEUE_TAIL_KEY].concat();
+        let mut all_keys = vec![&head_key[..], &tail_key[..]];
+        all_keys.extend_from_slice(keys);
+        if !batch_read(kvdb, &all_keys)? || requests == 0 {
+            return Ok(());
+        }
+        let index = |key: &[u8]| match kvdb.get(key) {
+            Ok(Some(raw)) => P::decode_u32(&raw).ok(),
+            Ok(None) => Some(0),
+            Err(_) => None,
+        };
+        let (Some(head), Some(tail)) = (index(&head_key), index(&tail_key)) else {
+            return Ok(());
+        };
+        let item_keys = queue_item_keys::<P>(queue_prefix, head, tail, requests);
+        if !item_keys.is_empty() {
+            let item_keys: Vec<&[u8]> = item_keys.iter().map(Vec::as_slice).collect();
+            batch_read(kvdb, &item_keys)?;
+        }
+        Ok(())
+    }
+}
+
+/// The keys of the queued requests `[head, min(tail, head + n))`
+fn queue_item_keys<P: Platform>(
+    queue_prefix: &[u8],
+    head: u32,
+    tail: u32,
+    n: usize,
+) -> Vec<Vec<u8>> {
+    let end = tail.min(head.saturating_add(n.try_into().unwrap_or(u32::MAX)));
+    (head..end)
+        .map(|index| [queue_prefix, &P::encode_u32(index)].concat())
+        .collect()
+}
+
+/// The endpoints to try in order, `rpc` and then `fallback_rpcs`
+pub(crate) fn endpoints<'r>(
+    rpc: &'r str,
+    fallback_rpcs: &'r [String],
+) -> impl Iterator<Item = &'r str> {
+    core::iter::once(rpc).chain(fallback_rpcs.iter().map(String::as_str))
+}
+
+/// Runs `connect` with the first endpoint that works, `rpc` and then `fallback_rpcs`
+///
+/// `connect` also gets the endpoints after the one it tries, to fail over to later, e.g. on
+/// submission. Only the network errors fail over to the next endpoint. Return the last error if
+/// all the endpoints failed.
+pub(crate) fn failover<'r, T>(
+    rpc: &'r str,
+    fallback_rpcs: &'r [String],
+    mut connect: impl FnMut(&'r str, &'r [String]) -> Result<T>,
+) -> Result<T> {
+    let mut last_err = Error::NoRpcEndpoint;
+    for (i, rpc) in endpoints(rpc, fallback_rpcs).enumerate() {
+        match connect(rpc, &fallback_rpcs[i..]) {
+            Ok(client) => return Ok(client),
+            Err(err) if err.kind() == ErrorKind::Network => {
+                #[cfg(feature = "logging")]
+                pink_extension::warn!("rpc {rpc} failed, trying the next one: {err}");
+                last_err = err;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Err(last_err)
+}
+
+/// The endpoint a snapshot reads from, failing over to the fallback endpoints
+///
+/// A read failed before any value was served is retried on the next endpoint. Once a value is
+/// served, the endpoint sticks, so all the values of the session come from the same endpoint.
+#[derive(Debug)]
+pub(crate) struct ReadEndpoint {
+    rpcs: Vec<String>,
+    current: Cell<usize>,
+    served: Cell<bool>,
+}
+
+impl ReadEndpoint {
+    pub fn new(rpc: &str, fallback_rpcs: &[String]) -> Self {
+        ReadEndpoint {
+            rpcs: endpoints(rpc, fallback_rpcs).map(Into::into).collect(),
+            current: Cell::new(0),
+            served: Cell::new(false),
+        }
+    }
+
+    /// The endpoint serving the reads now
+    pub fn current(&self) -> &str {
+        &self.rpcs[self.current.get()]
+    }
+
+    /// Marks a value served, e.g. a prefetched one, to stick to the current endpoint
+    pub fn served(&self) {
+        self.served.set(true);
+    }
+
+    /// Reads by `read` from the current endpoint, or the next ones until a value is served
+    pub fn read<T>(
+        &self,
+        mut read: impl FnMut(&str) -> kv_session::Result<T>,
+    ) -> kv_session::Result<T> {
+        loop {
+            match read(self.current()) {
+                Ok(value) => {
+                    self.served();
+                    return Ok(value);
+                }
+                Err(kv_session::Error::FailedToGetStorage)
+                    if !self.served.get() && self.current.get() + 1 < self.rpcs.len() =>
+                {
+                    #[cfg(feature = "logging")]
+                    pink_extension::warn!(
+                        "rpc {} failed, reading from the next one",
+                        self.current()
+                    );
+                    self.current.set(self.current.get() + 1);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl RollupResult {
+    /// Submits the tx to its target with `secret_key`. Returns the tx hash.
+    ///
+    /// A signed result is relayed to the ink! anchor by `RollupAnchor::rollup_signed`, where
+    /// `secret_key` only pays the fee. Otherwise `secret_key` must be allowed to submit to the
+    /// anchor. Fails with `Error::UnsupportedTarget` if the client of the target is not enabled.
+    pub fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        let target = self.target.ok_or(Error::MissingTarget)?;
+        match target {
+            #[cfg(feature = "evm")]
+            RollupTarget::Evm { rpc, contract_id } => {
+                if self.signature.is_some() {
+                    return Err(Error::UnsupportedSignature);
+                }
+                let submittable = evm::SubmittableRollupTx::new(&rpc, contract_id.into(), self.tx)?;
+                Submittable::submit(submittable, secret_key)
+            }
+            #[cfg(feature = "substrate")]
+            RollupTarget::Substrate {
+                rpc,
+                pallet_id,
+                contract_id,
+            } => {
+                if self.signature.is_some() {
+                    return Err(Error::UnsupportedSignature);
+                }
+                let contract_id = contract_id.into();
+                substrate::SubmittableRollupTx::new(&rpc, pallet_id, &contract_id, self.tx)
+                    .submit(secret_key)
+            }
+            #[cfg(feature = "ink")]
+            RollupTarget::Ink {
+                rpc,
+                pallet_id,
+                call_id,
+                contract_id,
+            } => {
+                let submittable =
+                    ink::SubmittableRollupTx::new(&rpc, pallet_id, call_id, &contract_id, self.tx)?;
+                match self.signature {
+                    Some(signature) => {
+                        let signature = signature.try_into().or(Err(Error::InvalidSignature))?;
+                        submittable.submit_signed(signature, secret_key)
+                    }
+                    None => submittable.submit(secret_key),
+                }
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::UnsupportedTarget),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failover_works() {
+        let connect = |rpc: &str, _: &[String]| match rpc {
+            "down" => Err(Error::FailedToGetBlockHash),
+            "bad" => Err(Error::UnsupportedCond),
+            rpc => Ok(rpc.len()),
+        };
+        let rpcs = |rpcs: &[&str]| {
+            rpcs.iter()
+                .map(|rpc| String::from(*rpc))
+                .collect::<Vec<_>>()
+        };
+        assert!(matches!(failover("down", &rpcs(&["ok"]), connect), Ok(2)));
+        assert!(matches!(failover("ok", &rpcs(&["down"]), connect), Ok(2)));
+        assert!(matches!(
+            failover("down", &rpcs(&["down", "ok"]), connect),
+            Ok(2)
+        ));
+        // Only the network errors fail over
+        assert!(matches!(
+            failover("bad", &rpcs(&["ok"]), connect),
+            Err(Error::UnsupportedCond)
+        ));
+        assert!(matches!(
+            failover("down", &rpcs(&["down"]), connect),
+            Err(Error::FailedToGetBlockHash)
+        ));
+        assert!(matches!(
+            failover("down", &[], connect),
+            Err(Error::FailedToGetBlockHash)
+        ));
+        // The endpoints after the chosen one are left to fail over to later
+        let fallback_rpcs = rpcs(&["down", "ok", "spare"]);
+        let rest = failover("down", &fallback_rpcs, |rpc, rest| {
+            connect(rpc, rest).map(|_| rest.to_vec())
+        });
+        assert_eq!(rest.unwrap(), rpcs(&["spare"]));
+    }
+
+    #[test]
+    fn read_endpoint_works() {
+        let read = |rpc: &str| match rpc {
+            "down" => Err(kv_session::Error::FailedToGetStorage),
+            "bad" => Err(kv_session::Error::FailedToDecode),
+            rpc => Ok(String::from(rpc)),
+        };
+        let fallback_rpcs = [String::from("down"), String::from("ok")];
+        let endpoint = ReadEndpoint::new("down", &fallback_rpcs);
+        assert_eq!(endpoint.read(read).unwrap(), "ok");
+        assert_eq!(endpoint.current(), "ok");
+        // Sticks to the endpoint serving the values
+        assert!(matches!(
+            endpoint.read(|_| Err::<(), _>(kv_session::Error::FailedToGetStorage)),
+            Err(kv_session::Error::FailedToGetStorage)
+        ));
+        assert_eq!(endpoint.current(), "ok");
+        // Only the failed reads fail over
+        let endpoint = ReadEndpoint::new("bad", &fallback_rpcs);
+        assert!(matches!(
+            endpoint.read(read),
+            Err(kv_session::Error::FailedToDecode)
+        ));
+        // Not after a value is served
+        let endpoint = ReadEndpoint::new("down", &fallback_rpcs);
+        endpoint.served();
+        assert!(matches!(
+            endpoint.read(read),
+            Err(kv_session::Error::FailedToGetStorage)
+        ));
+        assert_eq!(endpoint.current(), "down");
+    }
+
+    #[test]
+    fn client_options_work() {
+        let options = ClientOptions::<()>::default();
+        assert_eq!(options.mirrors(), None);
+        let options = options.with_mirrors(&["mirror"], 2);
+        assert_eq!(options.mirrors(), Some((&["mirror"][..], 2)));
+    }
+
+    #[test]
+    fn prefetch_queued_requests() {
+        use crate::platforms::Substrate;
+        use alloc::collections::BTreeMap;
+
+        /// A snapshot recording the batch reads
+        #[derive(Default)]
+        struct Snapshot {
+            prefetched: BTreeMap<Vec<u8>, Vec<u8>>,
+            batches: Vec<Vec<Vec<u8>>>,
+        }
+        impl KvSnapshot for Snapshot {
+            fn get(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+                Ok(self.prefetched.get(key).cloned())
+            }
+            fn snapshot_id(&self) -> kv_session::Result<Vec<u8>> {
+                Ok(Vec::new())
+            }
+        }
+        let anchor: BTreeMap<Vec<u8>, Vec<u8>> = [
+            (b"q/_head".to_vec(), 1u32.to_le_bytes().to_vec()),
+            (b"q/_tail".to_vec(), 3u32.to_le_bytes().to_vec()),
+        ]
+        .into();
+        let batch_read = |kvdb: &mut Snapshot, keys: &[&[u8]]| {
+            kvdb.batches
+                .push(keys.iter().map(|key| key.to_vec()).collect());
+            for key in keys {
+                if let Some(value) = anchor.get(*key) {
+                    kvdb.prefetched.insert(key.to_vec(), value.clone());
+                }
+            }
+            Ok(())
+        };
+
+        let mut kvdb = Snapshot::default();
+        let options = ClientOptions::<()>::default();
+        assert!(options
+            .prefetch::<Substrate, _>(&mut kvdb, b"q/", batch_read)
+            .is_ok());
+        assert!(kvdb.batches.is_empty());
+
+        let keys: &[&[u8]] = &[b"price"];
+        let options = options.with_prefetch(keys, 5);
+        assert!(options
+            .prefetch::<Substrate, _>(&mut kvdb, b"q/", batch_read)
+            .is_ok());
+        assert_eq!(
+            kvdb.batches,
+            vec![
+                vec![b"q/_head".to_vec(), b"q/_tail".to_vec(), b"price".to_vec()],
+                queue_item_keys::<Substrate>(b"q/", 1, 3, 5),
+            ]
+        );
+
+        // Only the disagreement of the endpoints fails
+        let mut kvdb = Snapshot::default();
+        let failed = |_: &mut Snapshot, _: &[&[u8]]| Err(Error::RpcNetworkError);
+        assert!(options
+            .prefetch::<Substrate, _>(&mut kvdb, b"q/", failed)
+            .is_ok());
+        let mismatch = |_: &mut Snapshot, _: &[&[u8]]| Err(Error::RpcMismatch);
+        assert!(matches!(
+            options.prefetch::<Substrate, _>(&mut kvdb, b"q/", mismatch),
+            Err(Error::RpcMismatch)
+        ));
+    }
+
+    #[test]
+    fn queue_item_keys_works() {
+        use crate::platforms::{Evm, Substrate};
+        assert_eq!(
+            queue_item_keys::<Substrate>(b"q/", 3, 10, 2),
+            vec![
+                [&b"q/"[..], &3u32.to_le_bytes()].concat(),
+                [&b"q/"[..], &4u32.to_le_bytes()].concat(),
+            ]
+        );
+        // Bounded by the tail
+        assert_eq!(queue_item_keys::<Substrate>(b"q/", 3, 4, 10).len(), 1);
+        assert!(queue_item_keys::<Substrate>(b"q/", 3, 3, 10).is_empty());
+        assert!(queue_item_keys::<Substrate>(b"q/", 3, 10, 0).is_empty());
+        assert_eq!(
+            queue_item_keys::<Evm>(b"q/", u32::MAX - 1, u32::MAX, usize::MAX),
+            vec![[&b"q/"[..], &Evm::encode_u32(u32::MAX - 1)].concat()]
+        );
+    }
+}
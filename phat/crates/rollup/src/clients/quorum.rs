@@ -0,0 +1,186 @@
+//! Cross validates the snapshot reads over multiple RPC endpoints
+//!
+//! A single endpoint can feed the session wrong values, either by lagging behind or on purpose.
+//! A cross validated snapshot reads every key from its primary endpoint and its mirrors, all
+//! pinned to the block of the primary endpoint. The value is accepted only if enough endpoints
+//! answered and all the answers are equal. Otherwise the read fails, and so does the session.
+//! A disagreement fails the session with `Error::RpcMismatch`, which is not retried.
+use crate::{Error, Result};
+use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+use core::cell::Cell;
+
+/// Remembers that the endpoints disagreed on a read
+///
+/// `kv_session::Error` can't tell a disagreement from a failed read, so the snapshot sets the
+/// flag, and the client reports the failed read as `Error::RpcMismatch`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Mismatch(Rc<Cell<bool>>);
+
+impl Mismatch {
+    fn set(&self) {
+        self.0.set(true);
+    }
+
+    pub fn found(&self) -> bool {
+        self.0.get()
+    }
+
+    /// Replaces the error of a read with `Error::RpcMismatch` if the endpoints disagreed
+    pub fn or(&self, err: Error) -> Error {
+        if self.found() {
+            Error::RpcMismatch
+        } else {
+            err
+        }
+    }
+}
+
+/// Returns the value agreed by the endpoints
+///
+/// The endpoints failed to answer are skipped. Fails if less than `quorum` endpoints answered,
+/// or any two answers differ, which is recorded in `mismatch`.
+pub(crate) fn cross_validate<T: PartialEq>(
+    answers: impl IntoIterator<Item = kv_session::Result<T>>,
+    quorum: usize,
+    mismatch: &Mismatch,
+) -> kv_session::Result<T> {
+    let mut agreed = None;
+    let mut votes = 0;
+    let mut last_err = None;
+    for answer in answers {
+        match answer {
+            Ok(value) => {
+                match &agreed {
+                    Some(agreed) if *agreed != value => {
+                        #[cfg(feature = "logging")]
+                        pink_extension::warn!("rollup snapshot: endpoints disagree");
+                        mismatch.set();
+                        return Err(kv_session::Error::FailedToGetStorage);
+                    }
+                    Some(_) => (),
+                    None => agreed = Some(value),
+                }
+                votes += 1;
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    match agreed {
+        Some(value) if votes >= quorum => Ok(value),
+        _ => Err(last_err.unwrap_or(kv_session::Error::FailedToGetStorage)),
+    }
+}
+
+/// Cross validates the values prefetched by the primary endpoint with the ones of the mirrors
+///
+/// The values not reaching the quorum, e.g. when the batch read of a mirror failed, are dropped
+/// to be read and cross validated one by one later. Fails with `Error::RpcMismatch` if any two
+/// endpoints disagree.
+pub(crate) fn validate_prefetched<V: PartialEq>(
+    primary: &mut BTreeMap<Vec<u8>, V>,
+    mirrors: &[&BTreeMap<Vec<u8>, V>],
+    quorum: usize,
+    mismatch: &Mismatch,
+) -> Result<()> {
+    primary.retain(|key, value| {
+        let answers = core::iter::once(Some(&*value))
+            .chain(mirrors.iter().map(|mirror| mirror.get(key)))
+            .map(|answer| answer.ok_or(kv_session::Error::FailedToGetStorage));
+        cross_validate(answers, quorum, mismatch).is_ok()
+    });
+    if mismatch.found() {
+        return Err(Error::RpcMismatch);
+    }
+    Ok(())
+}
+
+/// Checks that `quorum` can be reached by the primary endpoint and `num_mirrors` mirrors
+pub(crate) fn check_quorum(quorum: usize, num_mirrors: usize) -> Result<()> {
+    if quorum == 0 || quorum > num_mirrors + 1 {
+        return Err(Error::InvalidQuorum);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv_session::Error::{FailedToDecode, FailedToGetStorage};
+
+    #[test]
+    fn cross_validate_works() {
+        let mismatch = Mismatch::default();
+        assert!(matches!(
+            cross_validate([Ok(1), Ok(1), Ok(1)], 3, &mismatch),
+            Ok(1)
+        ));
+        // The failed endpoints are tolerated if the quorum is reached
+        assert!(matches!(
+            cross_validate([Ok(1), Err(FailedToGetStorage), Ok(1)], 2, &mismatch),
+            Ok(1)
+        ));
+        assert!(matches!(
+            cross_validate(
+                [Ok(1), Err(FailedToDecode), Err(FailedToDecode)],
+                2,
+                &mismatch
+            ),
+            Err(FailedToDecode)
+        ));
+        assert!(matches!(
+            cross_validate::<u32>([Err(FailedToDecode)], 1, &mismatch),
+            Err(FailedToDecode)
+        ));
+        assert!(!mismatch.found());
+        // Any mismatch fails the read, even if the quorum is reached
+        assert!(matches!(
+            cross_validate([Ok(1), Ok(1), Ok(2)], 2, &mismatch),
+            Err(FailedToGetStorage)
+        ));
+        assert!(mismatch.found());
+        assert!(matches!(
+            mismatch.or(Error::SessionFailedToGetStorage),
+            Error::RpcMismatch
+        ));
+
+        let mismatch = Mismatch::default();
+        assert!(matches!(
+            cross_validate([Ok(None), Ok(Some(1))], 1, &mismatch),
+            Err(FailedToGetStorage)
+        ));
+        assert!(mismatch.found());
+    }
+
+    #[test]
+    fn validate_prefetched_works() {
+        let map = |entries: &[(&[u8], u32)]| -> BTreeMap<Vec<u8>, u32> {
+            entries.iter().map(|(k, v)| (k.to_vec(), *v)).collect()
+        };
+        let mismatch = Mismatch::default();
+        // `b` is dropped for missing in the mirror, to be read one by one later
+        let mut primary = map(&[(b"a", 1), (b"b", 2)]);
+        let mirror = map(&[(b"a", 1)]);
+        assert!(validate_prefetched(&mut primary, &[&mirror], 2, &mismatch).is_ok());
+        assert_eq!(primary, map(&[(b"a", 1)]));
+        assert!(!mismatch.found());
+        // Tolerated by the quorum
+        let mut primary = map(&[(b"a", 1), (b"b", 2)]);
+        assert!(validate_prefetched(&mut primary, &[&mirror], 1, &mismatch).is_ok());
+        assert_eq!(primary.len(), 2);
+        // Disagreed
+        let mirror = map(&[(b"a", 3)]);
+        assert!(matches!(
+            validate_prefetched(&mut primary, &[&mirror], 1, &mismatch),
+            Err(Error::RpcMismatch)
+        ));
+        assert!(mismatch.found());
+    }
+
+    #[test]
+    fn check_quorum_works() {
+        assert!(check_quorum(1, 0).is_ok());
+        assert!(check_quorum(3, 2).is_ok());
+        assert!(matches!(check_quorum(0, 2), Err(Error::InvalidQuorum)));
+        assert!(matches!(check_quorum(4, 2), Err(Error::InvalidQuorum)));
+    }
+}
@@ -0,0 +1,291 @@
+//! Splits an oversized rollup tx into a sequence of dependent txs
+//!
+//! When a session pops many requests, the resulting rollup tx may exceed the block gas (or
+//! weight) limit of the target chain and can never be submitted. Instead, the actions are cut at
+//! the request boundaries into chunks under a `TxLimit`. Each chunk ends with a partial
+//! `ProcessedTo`, and each chunk but the first is conditioned on the queue head left by its
+//! predecessor, so the chunks can only be applied in order.
+//!
+//! The gas (or weight) of a tx is not known before it's estimated by the node, so it's bounded by
+//! proxies: the size of the encoded call, which bounds the calldata cost, and the number of the
+//! actions, which bounds the cost of handling them in the anchor. Pick the limits well below the
+//! block limit of the target chain. Only the EVM and ink! clients split. The Substrate pallet has
+//! no request queue, so its txs are never split.
+//!
+//! The kv updates of a session (including the lock versions) can't be attributed to individual
+//! requests. If they went with the first chunk, the requests of a chunk never applied would be
+//! answered again by the next session against the state already including their effects. So a
+//! session with kv updates is never split. The conditions of the session go to the first chunk.
+use crate::{Action, Cond, Raw};
+
+use alloc::{vec, vec::Vec};
+use kv_session::traits::QueueIndex;
+
+/// The actions attached in a session, each tagged with the number of requests popped before it
+#[derive(Default)]
+pub(crate) struct ActionLog {
+    actions: Vec<(u32, Action)>,
+    popped: u32,
+}
+
+impl ActionLog {
+    /// Records a popped request
+    pub fn popped(&mut self) {
+        self.popped += 1;
+    }
+
+    pub fn push(&mut self, action: Action) {
+        self.actions.push((self.popped, action));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+/// The limits of a single rollup tx. `None` for no limit.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TxLimit {
+    /// The max size of the encoded call, in bytes
+    pub max_size: Option<usize>,
+    /// The max number of the actions, including the `ProcessedTo`
+    pub max_actions: Option<usize>,
+}
+
+impl TxLimit {
+    fn is_unlimited(&self) -> bool {
+        self.max_size.is_none() && self.max_actions.is_none()
+    }
+
+    fn fits(&self, chunk: &Chunk, size_of: impl Fn(&Chunk) -> usize) -> bool {
+        self.max_actions
+            .map_or(true, |max| chunk.actions.len() <= max)
+            && self.max_size.map_or(true, |max| size_of(chunk) <= max)
+    }
+}
+
+/// A rollup tx produced by `split()`
+pub(crate) struct Chunk {
+    pub conds: Vec<Cond>,
+    pub updates: Vec<(Raw, Option<Raw>)>,
+    pub actions: Vec<Action>,
+}
+
+/// Builds the chunks of a committed session
+///
+/// - `head`: the queue head after the session, i.e. `queue_head` of the kv_session rollup tx
+/// - `limit`: the limits of a chunk
+/// - `head_cond`: builds the condition "the queue head equals to `n`" for the anchor
+/// - `size_of`: estimates the size of a chunk as submitted to the anchor
+///
+/// A chunk is never cut inside the actions of a single request, so a chunk may still exceed the
+/// limit if a single request is too large. Returns a single chunk if there's nothing to split, or
+/// if there are any `updates`.
+pub(crate) fn split(
+    conds: Vec<Cond>,
+    updates: Vec<(Raw, Option<Raw>)>,
+    log: ActionLog,
+    head: Option<QueueIndex>,
+    limit: TxLimit,
+    head_cond: impl Fn(QueueIndex) -> Cond,
+    size_of: impl Fn(&Chunk) -> usize,
+) -> Vec<Chunk> {
+    let mut first = Chunk {
+        conds,
+        updates,
+        actions: Vec::new(),
+    };
+    let (Some(head), false, true) = (head, limit.is_unlimited(), first.updates.is_empty()) else {
+        first.actions = log.actions.into_iter().map(|(_, a)| a).collect();
+        first.actions.extend(head.map(Action::ProcessedTo));
+        return vec![first];
+    };
+
+    // Group the actions by the queue head when they were attached. It's only safe to cut after
+    // a request is fully answered, i.e. between two groups with different marks. The actions
+    // attached before the first pop stick to the first request.
+    let start = head.saturating_sub(log.popped);
+    let mut groups: Vec<(QueueIndex, Vec<Action>)> = Vec::new();
+    for (popped, action) in log.actions {
+        let mark = start + popped;
+        match groups.last_mut() {
+            Some((end, actions)) if *end == mark || *end == start => {
+                *end = mark;
+                actions.push(action);
+            }
+            _ => groups.push((mark, vec![action])),
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut current = first;
+    let mut current_end: Option<QueueIndex> = None;
+    for (end, mut actions) in groups {
+        if let Some(prev_end) = current_end {
+            let len = current.actions.len();
+            current.actions.append(&mut actions);
+            current.actions.push(Action::ProcessedTo(end));
+            let fits = limit.fits(&current, &size_of);
+            current.actions.pop();
+            actions = current.actions.split_off(len);
+            if !fits {
+                current.actions.push(Action::ProcessedTo(prev_end));
+                chunks.push(current);
+                current = Chunk {
+                    conds: vec![head_cond(prev_end)],
+                    updates: Vec::new(),
+                    actions: Vec::new(),
+                };
+            }
+        }
+        current.actions.append(&mut actions);
+        current_end = Some(end);
+    }
+    current.actions.push(Action::ProcessedTo(head));
+    chunks.push(current);
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale::Encode;
+
+    fn head_cond(n: QueueIndex) -> Cond {
+        Cond::Eq(b"head".to_vec().into(), Some(n.encode().into()))
+    }
+
+    fn size_of(chunk: &Chunk) -> usize {
+        (&chunk.conds, &chunk.updates, &chunk.actions).encoded_size()
+    }
+
+    fn reply(n: u8) -> Action {
+        Action::Reply(vec![n; 16])
+    }
+
+    /// Pops `n` requests (starting at queue index 5) and answers each of them
+    fn answer(n: u8) -> ActionLog {
+        let mut log = ActionLog::default();
+        for i in 0..n {
+            log.popped();
+            log.push(reply(i));
+        }
+        log
+    }
+
+    fn update() -> Vec<(Raw, Option<Raw>)> {
+        vec![(b"k".to_vec().into(), Some(b"v".to_vec().into()))]
+    }
+
+    fn split_session(log: ActionLog, head: QueueIndex, max_size: Option<usize>) -> Vec<Chunk> {
+        let limit = TxLimit {
+            max_size,
+            max_actions: None,
+        };
+        split(vec![], vec![], log, Some(head), limit, head_cond, size_of)
+    }
+
+    #[test]
+    fn no_limit_no_split() {
+        let chunks = split_session(answer(10), 15, None);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].actions.len(), 11);
+        assert_eq!(chunks[0].actions[10], Action::ProcessedTo(15));
+    }
+
+    #[test]
+    fn split_at_request_boundaries() {
+        // Room for about three replies per chunk
+        let max_size = size_of(&Chunk {
+            conds: vec![head_cond(0)],
+            updates: vec![],
+            actions: vec![reply(0), reply(0), reply(0), Action::ProcessedTo(0)],
+        });
+        let chunks = split_session(answer(7), 12, Some(max_size));
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| size_of(c) <= max_size));
+
+        // Each chunk depends on the previous one
+        assert!(chunks[0].conds.is_empty());
+        assert_eq!(chunks[1].conds, vec![head_cond(8)]);
+        assert_eq!(chunks[2].conds, vec![head_cond(11)]);
+        // Each chunk processes the requests it answers
+        assert_eq!(
+            chunks[0].actions,
+            vec![reply(0), reply(1), reply(2), Action::ProcessedTo(8)]
+        );
+        assert_eq!(
+            chunks[1].actions,
+            vec![reply(3), reply(4), reply(5), Action::ProcessedTo(11)]
+        );
+        assert_eq!(chunks[2].actions, vec![reply(6), Action::ProcessedTo(12)]);
+    }
+
+    #[test]
+    fn never_split_a_request() {
+        let mut log = ActionLog::default();
+        // Actions before the first pop stick to the first request
+        log.push(reply(100));
+        log.popped();
+        log.push(reply(0));
+        log.push(reply(0));
+        log.popped();
+        log.push(reply(1));
+        let chunks = split_session(log, 2, Some(0));
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            chunks[0].actions,
+            vec![reply(100), reply(0), reply(0), Action::ProcessedTo(1)]
+        );
+        assert_eq!(chunks[1].actions, vec![reply(1), Action::ProcessedTo(2)]);
+    }
+
+    #[test]
+    fn split_by_action_count() {
+        let limit = TxLimit {
+            max_size: None,
+            max_actions: Some(3),
+        };
+        let chunks = split(
+            vec![],
+            vec![],
+            answer(5),
+            Some(10),
+            limit,
+            head_cond,
+            size_of,
+        );
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks[0].actions,
+            vec![reply(0), reply(1), Action::ProcessedTo(7)]
+        );
+        assert_eq!(
+            chunks[1].actions,
+            vec![reply(2), reply(3), Action::ProcessedTo(9)]
+        );
+        assert_eq!(chunks[2].actions, vec![reply(4), Action::ProcessedTo(10)]);
+    }
+
+    #[test]
+    fn never_split_with_updates() {
+        let limit = TxLimit {
+            max_size: Some(0),
+            max_actions: Some(1),
+        };
+        let chunks = split(
+            vec![head_cond(5)],
+            update(),
+            answer(5),
+            Some(10),
+            limit,
+            head_cond,
+            size_of,
+        );
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].conds, vec![head_cond(5)]);
+        assert_eq!(chunks[0].updates, update());
+        assert_eq!(chunks[0].actions.len(), 6);
+        assert_eq!(chunks[0].actions[5], Action::ProcessedTo(10));
+    }
+}
@@ -1,56 +1,167 @@
-use crate::{Action, Error, Result, RollupTx};
+use super::{
+    dedup, failover,
+    head::SnapshotHead,
+    jsonrpc,
+    quorum::{self, Mismatch},
+    ClientOptions, ReadEndpoint, RollupClient, Submittable,
+};
+use crate::{
+    lock::{self, LockAccess, LockVersion, LockVersionReader},
+    platforms::{Platform, PlatformCodec, Substrate},
+    Action, Cond, Error, Result, RollupResult, RollupTarget, RollupTx,
+};
 
-use alloc::{borrow::ToOwned, vec::Vec};
+use alloc::{borrow::ToOwned, collections::BTreeMap, format, string::String, vec, vec::Vec};
 use scale::{Decode, Encode};
+use serde::Deserialize;
 
 use kv_session::{
     rollup,
-    traits::{BumpVersion, KvSnapshot, QueueIndexCodec},
+    traits::{BumpVersion, KvSession, KvSnapshot, QueueSession},
     RwTracker, Session,
 };
 use pink::ResultExt;
 use pink_extension as pink;
+use pink_extension::chain_extension::signing;
 use pink_extension::AccountId;
 use primitive_types::H256;
 
 const METHOD_CLAIM_NAME: u8 = 0u8;
 const METHOD_ROLLUP: u8 = 1u8;
+/// The cache key prefixes of the last nonces sent by this worker
+const ROLLUP_NONCE_CACHE_PREFIX: &[u8] = b"rollup/rollup_nonce/";
+const ACCOUNT_NONCE_CACHE_PREFIX: &[u8] = b"rollup/account_nonce/";
+/// How long the last nonces are remembered, in seconds
+const NONCE_TTL_SECS: u64 = 60;
 
 pub struct SubstrateSnapshot<'a> {
-    rpc: &'a str,
+    endpoint: ReadEndpoint,
     contract_id: &'a AccountId,
     at: H256,
+    /// The values read by `prefetch()`, including the missing ones
+    prefetched: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// The snapshots of the other endpoints at the same block to cross validate the reads
+    mirrors: Vec<SubstrateSnapshot<'a>>,
+    quorum: usize,
+    mismatch: Mismatch,
 }
 
 impl<'a> SubstrateSnapshot<'a> {
     pub fn new(rpc: &'a str, contract_id: &'a AccountId) -> Result<Self> {
-        let hash = subrpc::get_block_hash(rpc, None).or(Err(Error::FailedToGetBlockHash))?;
-        Ok(SubstrateSnapshot {
-            rpc,
+        Self::new_with_head(rpc, contract_id, SnapshotHead::Best)
+    }
+
+    /// Creates a snapshot at the block selected by `head`
+    pub fn new_with_head(
+        rpc: &'a str,
+        contract_id: &'a AccountId,
+        head: SnapshotHead,
+    ) -> Result<Self> {
+        let hash = head.resolve(rpc)?;
+        Ok(Self::new_at(rpc, contract_id, hash))
+    }
+
+    /// Creates a snapshot at the block `at`
+    pub fn new_at(rpc: &'a str, contract_id: &'a AccountId, at: H256) -> Self {
+        SubstrateSnapshot {
+            endpoint: ReadEndpoint::new(rpc, &[]),
             contract_id,
-            at: hash,
-        })
+            at,
+            prefetched: Default::default(),
+            mirrors: Vec::new(),
+            quorum: 1,
+            mismatch: Default::default(),
+        }
     }
-}
 
-impl<'a> KvSnapshot for SubstrateSnapshot<'a> {
-    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+    /// Cross validates the reads with the `mirrors` endpoints at the same block
+    ///
+    /// A value is accepted if at least `quorum` endpoints, including this one, answered and all
+    /// the answers are equal. See `clients::quorum`.
+    pub fn with_mirrors(mut self, mirrors: &[&'a str], quorum: usize) -> Result<Self> {
+        quorum::check_quorum(quorum, mirrors.len())?;
+        self.mirrors = mirrors
+            .iter()
+            .map(|&rpc| Self::new_at(rpc, self.contract_id, self.at))
+            .collect();
+        self.quorum = quorum;
+        Ok(self)
+    }
+
+    /// Reads from `fallback_rpcs` in order if the endpoint fails before serving any value
+    ///
+    /// The fallback endpoints read at the same block. See `ReadEndpoint`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &[String]) -> Self {
+        self.endpoint = ReadEndpoint::new(self.endpoint.current(), fallback_rpcs);
+        self
+    }
+
+    /// Reads the values of `keys` at the snapshot block with a single `state_queryStorageAt`
+    /// request
+    ///
+    /// The values are served by `get()` later without hitting the RPC. With mirrors, only the
+    /// values reaching the quorum are kept. See `quorum::validate_prefetched()`.
+    pub fn prefetch(&mut self, keys: &[&[u8]]) -> Result<()> {
+        for mirror in &mut self.mirrors {
+            // The values the mirror failed to prefetch don't count for the quorum
+            let _ = mirror.prefetch(keys);
+        }
+        let storage_keys: BTreeMap<Vec<u8>, &[u8]> = keys
+            .iter()
+            .map(|key| (self.storage_key(key), *key))
+            .collect();
+        let changes = query_storage_at(self.endpoint.current(), storage_keys.keys(), self.at)?;
+        for (storage_key, value) in changes {
+            let Some(key) = storage_keys.get(&storage_key) else {
+                continue;
+            };
+            let value = match value {
+                Some(raw) => {
+                    Some(Vec::<u8>::decode(&mut &raw[..]).or(Err(Error::FailedToDecodeStorage))?)
+                }
+                None => None,
+            };
+            self.prefetched.insert(key.to_vec(), value);
+        }
+        if self.mirrors.is_empty() {
+            return Ok(());
+        }
+        let mirrors: Vec<_> = self.mirrors.iter().map(|m| &m.prefetched).collect();
+        quorum::validate_prefetched(&mut self.prefetched, &mirrors, self.quorum, &self.mismatch)
+    }
+
+    /// The flag set if the endpoints disagreed on a read
+    pub(crate) fn mismatch(&self) -> Mismatch {
+        self.mismatch.clone()
+    }
+
+    fn storage_key(&self, key: &[u8]) -> Vec<u8> {
         let prefix = subrpc::storage::storage_prefix("PhatRollupAnchor", "States");
         let key1: &[u8] = self.contract_id.as_ref();
         let key2: &[u8] = &key.to_owned().encode();
-        let storage_key = subrpc::storage::storage_double_map_prefix::<
+        subrpc::storage::storage_double_map_prefix::<
             subrpc::hasher::Blake2_128Concat,
             subrpc::hasher::Blake2_128Concat,
-        >(&prefix, key1, key2);
-        let value = subrpc::get_storage(self.rpc, &storage_key, None)
-            .log_err("rollup snapshot: get storage failed")
-            .or(Err(kv_session::Error::FailedToGetStorage))?;
+        >(&prefix, key1, key2)
+    }
+
+    /// Reads the value from this endpoint only
+    fn read(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+        if let Some(value) = self.prefetched.get(key) {
+            self.endpoint.served();
+            return Ok(value.clone());
+        }
+        let storage_key = self.storage_key(key);
+        let value = self.endpoint.read(|rpc| {
+            subrpc::get_storage(rpc, &storage_key, Some(self.at))
+                .log_err("rollup snapshot: get storage failed")
+                .or(Err(kv_session::Error::FailedToGetStorage))
+        })?;
 
         #[cfg(feature = "logging")]
         pink::warn!(
-            "Storage[{}][{}] = {:?}",
-            hex::encode(key1),
-            hex::encode(key2),
+            "Storage[{}] = {:?}",
+            hex::encode(&storage_key),
             value.clone().map(|data| hex::encode(&data))
         );
 
@@ -61,6 +172,21 @@ impl<'a> KvSnapshot for SubstrateSnapshot<'a> {
             None => Ok(None),
         }
     }
+}
+
+impl<'a> KvSnapshot for SubstrateSnapshot<'a> {
+    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Vec<u8>>> {
+        if self.mirrors.is_empty() {
+            return self.read(key);
+        }
+        quorum::cross_validate(
+            core::iter::once(self)
+                .chain(&self.mirrors)
+                .map(|snapshot| snapshot.read(key)),
+            self.quorum,
+            &self.mismatch,
+        )
+    }
 
     fn snapshot_id(&self) -> kv_session::Result<Vec<u8>> {
         Ok(self.at.encode())
@@ -68,40 +194,34 @@ impl<'a> KvSnapshot for SubstrateSnapshot<'a> {
 }
 impl<'a> BumpVersion for SubstrateSnapshot<'a> {
     fn bump_version(&self, version: Option<Vec<u8>>) -> kv_session::Result<Vec<u8>> {
-        match version {
-            Some(v) => {
-                let ver = u32::decode(&mut &v[..]).or(Err(kv_session::Error::FailedToDecode))?;
-                Ok((ver + 1).encode())
-            }
-            None => Ok(1u32.encode()),
-        }
+        Substrate::bump_version(version.as_deref()).or(Err(kv_session::Error::FailedToDecode))
     }
 }
 
-pub struct ScaleCodec;
-impl QueueIndexCodec for ScaleCodec {
-    fn encode(number: u32) -> Vec<u8> {
-        number.encode()
-    }
-
-    fn decode(raw: impl AsRef<[u8]>) -> kv_session::Result<u32> {
-        // QueueIndex is stored as a value in the rollup kv store. When the value is empty, it's
-        // treated as the default value (0 for u32). However, this function only handles the
-        // non-empty case (empty value != zero length bytes). So here, `[]` is not considered.
-        Decode::decode(&mut raw.as_ref()).or(Err(kv_session::Error::FailedToDecode))
+impl<'a> LockVersionReader for SubstrateSnapshot<'a> {
+    fn get_version(&self, key: &[u8]) -> Result<LockVersion> {
+        lock::read_version::<Substrate>(self, key)
     }
 }
 
+pub type ScaleCodec = PlatformCodec<Substrate>;
+
 pub struct SubstrateRollupClient<'a> {
     rpc: &'a str,
+    /// The endpoints to fail over to on submission
+    fallback_rpcs: &'a [String],
     pallet_id: u8,
     contract_id: &'a AccountId,
     actions: Vec<Vec<u8>>,
+    conds: Vec<Cond>,
+    locks: Vec<LockAccess<Substrate>>,
     session: Session<SubstrateSnapshot<'a>, RwTracker, ScaleCodec>,
+    mismatch: Mismatch,
 }
 
 pub struct SubmittableRollupTx<'a> {
     rpc: &'a str,
+    fallback_rpcs: &'a [String],
     pallet_id: u8,
     contract_id: &'a AccountId,
     tx: RollupTx,
@@ -114,15 +234,65 @@ impl<'a> SubstrateRollupClient<'a> {
         contract_id: &'a AccountId,
         queue_prefix: &[u8],
     ) -> Result<Self> {
-        let kvdb = SubstrateSnapshot::new(rpc, contract_id)?;
+        Self::new_with_options(
+            rpc,
+            pallet_id,
+            contract_id,
+            queue_prefix,
+            &Default::default(),
+        )
+    }
+
+    /// Creates a client with the failover, the snapshot block, the cross validation and the
+    /// prefetch set by `options`
+    ///
+    /// See `ClientOptions` and `clients::head`.
+    pub fn new_with_options(
+        rpc: &'a str,
+        pallet_id: u8,
+        contract_id: &'a AccountId,
+        queue_prefix: &[u8],
+        options: &ClientOptions<'a, SnapshotHead>,
+    ) -> Result<Self> {
+        failover(rpc, options.fallback_rpcs(), |rpc, fallback_rpcs| {
+            let kvdb = SubstrateSnapshot::new_with_head(rpc, contract_id, *options.block())?
+                .with_fallback_rpcs(fallback_rpcs);
+            let client =
+                Self::with_snapshot(rpc, pallet_id, contract_id, queue_prefix, kvdb, options)?;
+            // Submit to the endpoints after the chosen one if it fails
+            Ok(SubstrateRollupClient {
+                fallback_rpcs,
+                ..client
+            })
+        })
+    }
+
+    fn with_snapshot(
+        rpc: &'a str,
+        pallet_id: u8,
+        contract_id: &'a AccountId,
+        queue_prefix: &[u8],
+        mut kvdb: SubstrateSnapshot<'a>,
+        options: &ClientOptions<'a, SnapshotHead>,
+    ) -> Result<Self> {
+        if let Some((mirrors, quorum)) = options.mirrors() {
+            kvdb = kvdb.with_mirrors(mirrors, quorum)?;
+        }
+        options
+            .prefetch::<Substrate, _>(&mut kvdb, queue_prefix, |kvdb, keys| kvdb.prefetch(keys))?;
+        let mismatch = kvdb.mismatch();
         let access_tracker = RwTracker::new();
         Ok(SubstrateRollupClient {
             rpc,
+            fallback_rpcs: &[],
             pallet_id,
             contract_id,
             actions: Default::default(),
+            conds: Default::default(),
+            locks: Default::default(),
             session: Session::new(kvdb, access_tracker, queue_prefix)
                 .map_err(Error::SessionError)?,
+            mismatch,
         })
     }
 
@@ -130,12 +300,74 @@ impl<'a> SubstrateRollupClient<'a> {
         &mut self.session
     }
 
+    /// Attaches an action to the rollup tx. See `RollupClient::action()`.
     pub fn action(&mut self, action: Action) -> &mut Self {
+        RollupClient::action(self, action)
+    }
+
+    /// Commits the session as a rollup tx. See `RollupClient::commit()`.
+    pub fn commit(self) -> Result<Option<SubmittableRollupTx<'a>>> {
+        RollupClient::commit(self)
+    }
+
+    fn convert_err(err: kv_session::Error) -> Error {
+        match err {
+            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
+            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+        }
+    }
+}
+
+impl<'a> RollupClient for SubstrateRollupClient<'a> {
+    type Submittable = SubmittableRollupTx<'a>;
+    type Platform = Substrate;
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        self.session
+            .get(key)
+            .map_err(|e| mismatch.or(Self::convert_err(e)))
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        self.session.put(key, value);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.session.delete(key);
+    }
+
+    fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        let mismatch = &self.mismatch;
+        self.session
+            .pop()
+            .map_err(|e| mismatch.or(Self::convert_err(e)))
+    }
+
+    fn action(&mut self, action: Action) -> &mut Self {
         self.actions.push(action.encode());
         self
     }
 
-    pub fn commit(self) -> Result<Option<SubmittableRollupTx<'a>>> {
+    fn cond(&mut self, cond: Cond) -> &mut Self {
+        self.conds.push(cond);
+        self
+    }
+
+    fn lock(&mut self, access: LockAccess<Self::Platform>) -> &mut Self {
+        self.locks.push(access);
+        self
+    }
+
+    /// Commits the session as a single rollup tx
+    ///
+    /// The pallet anchor has no request queue, so no `ProcessedTo` is attached, and the tx is
+    /// never split.
+    fn commit_split(self) -> Result<Vec<SubmittableRollupTx<'a>>> {
+        // The pallet anchor only supports `Eq`
+        if !self.conds.iter().all(|c| matches!(c, Cond::Eq(..))) {
+            return Err(Error::UnsupportedCond);
+        }
         let (session_tx, kvdb) = self.session.commit();
         let raw_tx = rollup::rollup(
             &kvdb,
@@ -144,25 +376,21 @@ impl<'a> SubstrateRollupClient<'a> {
                 key_postfix: b":ver".to_vec(),
             },
         )
-        .map_err(Self::convert_err)?;
+        .map_err(|e| self.mismatch.or(Self::convert_err(e)))?;
 
         // #[cfg(feature = "logging")]
         // pink::warn!("RawTx: {raw_tx:?}");
 
         if raw_tx.updates.is_empty() && self.actions.is_empty() {
-            return Ok(None);
+            return Ok(Vec::new());
         }
 
-        // Consider:
-        // if let Some(head_idx) = raw_tx.queue_head {
-        //     self.action(Action::ProcessedTo(head_idx));
-        // }
-
-        let tx = crate::RollupTx {
+        let mut tx = RollupTx {
             conds: raw_tx
                 .conditions
                 .into_iter()
-                .map(|(k, v)| crate::Cond::Eq(k.into(), v.map(Into::into)))
+                .map(|(k, v)| Cond::Eq(k.into(), v.map(Into::into)))
+                .chain(self.conds)
                 .collect(),
             actions: self.actions.into_iter().map(Into::into).collect(),
             updates: raw_tx
@@ -171,35 +399,117 @@ impl<'a> SubstrateRollupClient<'a> {
                 .map(|(k, v)| (k.into(), v.map(Into::into)))
                 .collect(),
         };
+        for access in &self.locks {
+            access
+                .apply(&mut tx, &kvdb)
+                .map_err(|e| self.mismatch.or(e))?;
+        }
+        tx.normalize()?;
 
-        Ok(Some(SubmittableRollupTx {
+        Ok(vec![SubmittableRollupTx {
             rpc: self.rpc,
+            fallback_rpcs: self.fallback_rpcs,
             pallet_id: self.pallet_id,
             contract_id: self.contract_id,
             tx,
-        }))
+        }])
     }
+}
 
-    fn convert_err(err: kv_session::Error) -> Error {
-        match err {
-            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
-            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+impl<'a> SubmittableRollupTx<'a> {
+    /// Prepares a rollup tx committed elsewhere, e.g. the tx of a `RollupResult`, to submit to
+    /// the pallet anchor
+    ///
+    /// The actions must be SCALE encoded `Action`s, as in `into_rollup_tx()`.
+    pub fn new(rpc: &'a str, pallet_id: u8, contract_id: &'a AccountId, tx: RollupTx) -> Self {
+        SubmittableRollupTx {
+            rpc,
+            fallback_rpcs: &[],
+            pallet_id,
+            contract_id,
+            tx,
         }
     }
-}
 
-impl<'a> SubmittableRollupTx<'a> {
-    pub fn submit(self, secret_key: &[u8; 32], nonce: u128) -> Result<Vec<u8>> {
+    /// Submits to `fallback_rpcs` in order if the endpoint fails. See `failover()`.
+    pub fn with_fallback_rpcs(mut self, fallback_rpcs: &'a [String]) -> Self {
+        self.fallback_rpcs = fallback_rpcs;
+        self
+    }
+
+    /// Returns the rollup tx, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_rollup_tx(self) -> RollupTx {
+        self.tx
+    }
+
+    /// Returns the rollup tx with its target, e.g. to answer `RollupHandler::handle_rollup()`
+    pub fn into_result(self) -> RollupResult {
+        RollupResult {
+            target: Some(RollupTarget::Substrate {
+                rpc: self.rpc.to_owned(),
+                pallet_id: self.pallet_id,
+                contract_id: *self.contract_id.as_ref(),
+            }),
+            tx: self.tx,
+            signature: None,
+        }
+    }
+
+    /// Submits the tx with the rollup nonce of the anchor. Returns the tx hash.
+    ///
+    /// The rollup nonce is read from the pallet, which only accepts the tx with the current nonce
+    /// of the anchor and bumps it. The last rollup nonce and account nonce sent by this worker are
+    /// tracked in the local cache, so the back-to-back submissions don't reuse them before the
+    /// previous tx is included. See `pick_nonce()`. The tx is submitted to the fallback endpoints
+    /// in order if the endpoint fails.
+    ///
+    /// Fails with `Error::DuplicateSubmission` if the same tx was submitted recently. See
+    /// `clients::dedup`.
+    pub fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        let dedup_key = dedup::cache_key(self.contract_id.as_ref(), &self.tx);
+        dedup::ensure_not_submitted(&dedup_key)?;
+
+        let account: [u8; 32] = signing::get_public_key(secret_key, signing::SigType::Sr25519)
+            .try_into()
+            .expect("Sr25519 public key must be 32 bytes; qed.");
+        let tx_hash = failover(self.rpc, self.fallback_rpcs, |rpc, _| {
+            self.submit_to(rpc, secret_key, &account)
+        })?;
+        dedup::record_submitted(&dedup_key, &tx_hash);
+        Ok(tx_hash)
+    }
+
+    fn submit_to(&self, rpc: &str, secret_key: &[u8; 32], account: &[u8; 32]) -> Result<Vec<u8>> {
+        let rollup_nonce_key = nonce_cache_key(ROLLUP_NONCE_CACHE_PREFIX, rpc, self.contract_id);
+        let account_nonce_key = nonce_cache_key(ACCOUNT_NONCE_CACHE_PREFIX, rpc, account);
+        let chain = read_chain_nonces(rpc, self.contract_id, account)?;
+        let in_pool = |tx_hash: &[u8]| chain.pool.iter().any(|hash| hash[..] == *tx_hash);
+        let nonce = pick_nonce(
+            chain.rollup_nonce,
+            load_sent_nonce(&rollup_nonce_key),
+            in_pool,
+        );
+        let account_nonce = pick_nonce(
+            chain.account_nonce.into(),
+            load_sent_nonce(&account_nonce_key),
+            in_pool,
+        );
+        let account_nonce = account_nonce
+            .try_into()
+            .or(Err(Error::FailedToDecodeStorage))?;
         let signed_tx = subrpc::create_transaction(
             secret_key,
             "khala",
-            self.rpc,
-            self.pallet_id,                     // pallet idx
-            METHOD_ROLLUP,                      // method 1: rollup
-            (self.contract_id, self.tx, nonce), // (name, tx, nonce)
-            subrpc::ExtraParam::default(),
+            rpc,
+            self.pallet_id,                      // pallet idx
+            METHOD_ROLLUP,                       // method 1: rollup
+            (self.contract_id, &self.tx, nonce), // (name, tx, nonce)
+            subrpc::ExtraParam {
+                nonce: Some(account_nonce),
+                ..Default::default()
+            },
         )
-        .or(Err(Error::FailedToCreateTransaction))?;
+        .map_err(Error::SubstrateFailedToCreateTransaction)?;
 
         #[cfg(feature = "logging")]
         {
@@ -207,15 +517,176 @@ impl<'a> SubmittableRollupTx<'a> {
             pink::warn!("SignedTx = {}", hex::encode(&signed_tx),);
         }
 
-        let tx_hash = subrpc::send_transaction(self.rpc, &signed_tx)
-            .or(Err(Error::FailedToSendTransaction))?;
+        let tx_hash = jsonrpc::submit_extrinsic(rpc, &signed_tx)?;
 
         #[cfg(feature = "logging")]
         pink::warn!("Sent = {}", hex::encode(&tx_hash),);
+        record_sent_nonce(&rollup_nonce_key, nonce, &tx_hash);
+        record_sent_nonce(&account_nonce_key, account_nonce.into(), &tx_hash);
         Ok(tx_hash)
     }
 }
 
+impl<'a> Submittable for SubmittableRollupTx<'a> {
+    fn submit(self, secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        SubmittableRollupTx::submit(self, secret_key)
+    }
+}
+
+/// The last nonce sent by this worker, and the hash of the tx sent with it
+type SentNonce = (u128, Vec<u8>);
+
+/// Picks the nonce of the next tx, from the nonce on chain and the last one sent by this worker
+///
+/// The last tx may not be included yet, so its nonce is skipped while the tx is in the pool.
+/// Once the tx left the pool without bumping the nonce on chain, e.g. dropped or invalidated,
+/// the nonce on chain is used again.
+fn pick_nonce(
+    chain_nonce: u128,
+    last_sent: Option<SentNonce>,
+    in_pool: impl Fn(&[u8]) -> bool,
+) -> u128 {
+    match last_sent {
+        Some((nonce, tx_hash)) if nonce >= chain_nonce && in_pool(&tx_hash) => nonce + 1,
+        _ => chain_nonce,
+    }
+}
+
+/// The chain state the nonces of the next rollup tx are picked from
+struct ChainNonces {
+    /// The rollup nonce of the anchor in the pallet
+    rollup_nonce: u128,
+    /// The nonce of the sender account
+    account_nonce: u32,
+    /// The hashes of the txs in the pool
+    pool: Vec<[u8; 32]>,
+}
+
+/// Reads the pool of `rpc`, then the rollup nonce and the account nonce in a single batch request
+///
+/// The pool is read first. A tx leaving the pool to be included is then seen by the nonces.
+fn read_chain_nonces(
+    rpc: &str,
+    contract_id: &AccountId,
+    account: &[u8; 32],
+) -> Result<ChainNonces> {
+    let pool = pending_tx_hashes(rpc)?;
+    let keys = [
+        rollup_nonce_storage_key(contract_id),
+        account_storage_key(account),
+    ]
+    .map(|key| format!("\"0x{}\"", hex::encode(key)));
+    let resp = jsonrpc::batch(
+        rpc,
+        "state_getStorage",
+        &[keys[0].as_str(), keys[1].as_str()],
+    )?;
+    let [rollup_nonce, account_nonce] = jsonrpc::parse_batch(&resp, 2)?[..] else {
+        return Err(Error::FailedToDecode);
+    };
+    Ok(ChainNonces {
+        rollup_nonce: decode_storage(rollup_nonce)?.unwrap_or_default(),
+        // `AccountInfo.nonce` is the first field. 0 if the account doesn't exist.
+        account_nonce: decode_storage(account_nonce)?.unwrap_or_default(),
+        pool,
+    })
+}
+
+/// The storage key of the rollup nonce of the anchor `contract_id` in the pallet
+fn rollup_nonce_storage_key(contract_id: &AccountId) -> Vec<u8> {
+    let prefix = subrpc::storage::storage_prefix("PhatRollupAnchor", "Nonces");
+    subrpc::storage::storage_map_prefix::<subrpc::hasher::Blake2_128Concat>(
+        &prefix,
+        contract_id.as_ref(),
+    )
+}
+
+/// The storage key of the `AccountInfo` of `account`
+fn account_storage_key(account: &[u8; 32]) -> Vec<u8> {
+    let prefix = subrpc::storage::storage_prefix("System", "Account");
+    subrpc::storage::storage_map_prefix::<subrpc::hasher::Blake2_128Concat>(&prefix, account)
+}
+
+/// Decodes a storage value read by `state_getStorage`, `None` if the storage is absent
+fn decode_storage<T: Decode>(value: Option<&str>) -> Result<Option<T>> {
+    value
+        .map(|value| {
+            let raw = jsonrpc::decode_hex(value).or(Err(Error::FailedToDecodeStorage))?;
+            T::decode(&mut &raw[..]).or(Err(Error::FailedToDecodeStorage))
+        })
+        .transpose()
+}
+
+/// Gets the hashes of the txs in the pool of `rpc`
+fn pending_tx_hashes(rpc: &str) -> Result<Vec<[u8; 32]>> {
+    let resp = jsonrpc::call(rpc, "author_pendingExtrinsics", "")?;
+    parse_pending_tx_hashes(&resp)
+}
+
+fn parse_pending_tx_hashes(resp: &[u8]) -> Result<Vec<[u8; 32]>> {
+    let txs: Vec<&str> = jsonrpc::parse(resp)?.unwrap_or_default();
+    txs.into_iter()
+        .map(|tx| {
+            let tx = jsonrpc::decode_hex(tx).or(Err(Error::FailedToDecode))?;
+            let mut hash = [0u8; 32];
+            ink::env::hash_bytes::<ink::env::hash::Blake2x256>(&tx, &mut hash);
+            Ok(hash)
+        })
+        .collect()
+}
+
+fn load_sent_nonce(key: &[u8]) -> Option<SentNonce> {
+    pink::ext()
+        .cache_get(key)
+        .and_then(|raw| Decode::decode(&mut &raw[..]).ok())
+}
+
+/// Remembers the nonce sent with the tx `tx_hash`
+fn record_sent_nonce(key: &[u8], nonce: u128, tx_hash: &[u8]) {
+    if pink::ext()
+        .cache_set(key, &(nonce, tx_hash).encode())
+        .is_ok()
+    {
+        pink::ext().cache_set_expiration(key, NONCE_TTL_SECS);
+    }
+}
+
+fn nonce_cache_key(prefix: &[u8], rpc: &str, id: &impl AsRef<[u8]>) -> Vec<u8> {
+    [prefix, id.as_ref(), rpc.as_bytes()].concat()
+}
+
+#[derive(Deserialize)]
+struct StorageChangeSet<'a> {
+    #[serde(borrow)]
+    changes: Vec<(&'a str, Option<&'a str>)>,
+}
+
+/// Reads the storage of `keys` at the block `at` with `state_queryStorageAt`
+///
+/// Returns the `(storage_key, value)` pairs. The missing keys have the value `None`.
+fn query_storage_at<'k>(
+    rpc: &str,
+    keys: impl Iterator<Item = &'k Vec<u8>>,
+    at: H256,
+) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+    let keys: Vec<String> = keys
+        .map(|key| format!("\"0x{}\"", hex::encode(key)))
+        .collect();
+    let params = format!("[{}],\"0x{}\"", keys.join(","), hex::encode(at));
+    let resp = jsonrpc::call(rpc, "state_queryStorageAt", &params)?;
+    parse_storage_changes(&resp)
+}
+
+fn parse_storage_changes(resp: &[u8]) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+    let change_sets: Vec<StorageChangeSet> = jsonrpc::parse(resp)?.unwrap_or_default();
+    let decode_hex = |s: &str| jsonrpc::decode_hex(s).or(Err(Error::FailedToDecodeStorage));
+    change_sets
+        .into_iter()
+        .flat_map(|change_set| change_set.changes)
+        .map(|(key, value)| Ok((decode_hex(key)?, value.map(decode_hex).transpose()?)))
+        .collect()
+}
+
 pub fn get_name_owner(rpc: &str, contract_id: &AccountId) -> Result<Option<AccountId>> {
     // Build key
     let prefix = subrpc::storage::storage_prefix("PhatRollupAnchor", "SubmitterByNames");
@@ -246,12 +717,158 @@ pub fn claim_name(
         contract_id,
         subrpc::ExtraParam::default(),
     )
-    .or(Err(Error::FailedToCreateTransaction))?;
+    .map_err(Error::SubstrateFailedToCreateTransaction)?;
 
-    let tx_hash =
-        subrpc::send_transaction(rpc, &signed_tx).or(Err(Error::FailedToSendTransaction))?;
+    let tx_hash = jsonrpc::submit_extrinsic(rpc, &signed_tx)?;
 
     #[cfg(feature = "logging")]
     pink::warn!("Sent = {}", hex::encode(&tx_hash),);
     Ok(tx_hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RPC: &str = "http://127.0.0.1:39933";
+
+    /// Creates a client of an empty queue served by the prefetched values, without the RPC
+    fn offline_client(contract_id: &AccountId) -> SubstrateRollupClient {
+        let mut kvdb = SubstrateSnapshot::new_at(RPC, contract_id, H256::zero());
+        for key in [&b"q/_head"[..], b"q/_tail"] {
+            kvdb.prefetched.insert(key.to_vec(), None);
+        }
+        SubstrateRollupClient::with_snapshot(
+            RPC,
+            100,
+            contract_id,
+            b"q/",
+            kvdb,
+            &Default::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn only_eq_cond_is_supported() {
+        pink_extension_runtime::mock_ext::mock_all_ext();
+        let contract_id = AccountId::from([1u8; 32]);
+
+        let mut client = offline_client(&contract_id);
+        client
+            .cond(Cond::Eq(b"k".to_vec().into(), None))
+            .action(Action::Reply(b"r".to_vec()));
+        let txs = client.commit_split().unwrap();
+        assert_eq!(txs.len(), 1);
+        // No condition other than the ones of the session, so the sessions on the other keys
+        // don't conflict
+        assert_eq!(txs[0].tx.conds, vec![Cond::Eq(b"k".to_vec().into(), None)]);
+        assert!(txs[0].tx.updates.is_empty());
+
+        for cond in [
+            Cond::Ne(b"k".to_vec().into(), None),
+            Cond::Exists(b"k".to_vec().into()),
+            Cond::NotExists(b"k".to_vec().into()),
+            Cond::Le(b"k".to_vec().into(), 1),
+            Cond::Ge(b"k".to_vec().into(), 1),
+        ] {
+            let mut client = offline_client(&contract_id);
+            client.cond(cond).action(Action::Reply(b"r".to_vec()));
+            assert!(matches!(client.commit_split(), Err(Error::UnsupportedCond)));
+        }
+    }
+
+    #[test]
+    fn pick_rollup_nonce() {
+        let in_pool = |tx_hash: &[u8]| tx_hash == b"pending";
+        assert_eq!(pick_nonce(3, None, in_pool), 3);
+        // The last tx is not included yet
+        assert_eq!(pick_nonce(3, Some((3, b"pending".to_vec())), in_pool), 4);
+        assert_eq!(pick_nonce(3, Some((4, b"pending".to_vec())), in_pool), 5);
+        // The last tx was dropped
+        assert_eq!(pick_nonce(3, Some((4, b"dropped".to_vec())), in_pool), 3);
+        // The last tx was included, even if the pool still lists it
+        assert_eq!(pick_nonce(4, Some((3, b"pending".to_vec())), in_pool), 4);
+    }
+
+    #[test]
+    fn pick_account_nonce() {
+        pink_extension_runtime::mock_ext::mock_all_ext();
+        let key = nonce_cache_key(ACCOUNT_NONCE_CACHE_PREFIX, RPC, &[1u8; 32]);
+        assert_eq!(load_sent_nonce(&key), None);
+        record_sent_nonce(&key, 7, b"tx");
+        // Back-to-back submissions skip the nonce of the tx in the pool
+        let last_sent = load_sent_nonce(&key);
+        assert_eq!(last_sent, Some((7, b"tx".to_vec())));
+        assert_eq!(pick_nonce(7, last_sent.clone(), |_| true), 8);
+        // Falls back to the nonce on chain once the tx left the pool
+        assert_eq!(pick_nonce(7, last_sent, |_| false), 7);
+        // The nonces are tracked per account and per endpoint
+        assert_eq!(
+            load_sent_nonce(&nonce_cache_key(
+                ACCOUNT_NONCE_CACHE_PREFIX,
+                RPC,
+                &[2u8; 32]
+            )),
+            None
+        );
+        assert_eq!(
+            load_sent_nonce(&nonce_cache_key(
+                ACCOUNT_NONCE_CACHE_PREFIX,
+                "http://127.0.0.1:9944",
+                &[1u8; 32]
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_pending_txs() {
+        let resp = br#"{"jsonrpc":"2.0","result":["0x0102","0x03"],"id":1}"#;
+        let hashes = parse_pending_tx_hashes(resp).unwrap();
+        let mut expected = [0u8; 32];
+        ink::env::hash_bytes::<ink::env::hash::Blake2x256>(&[1, 2], &mut expected);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[0], expected);
+        assert!(matches!(
+            parse_pending_tx_hashes(br#"{"jsonrpc":"2.0","result":["0xzz"],"id":1}"#),
+            Err(Error::FailedToDecode)
+        ));
+    }
+
+    #[test]
+    fn parse_storage_changes_works() {
+        let resp = br#"{"jsonrpc":"2.0","result":[{"block":"0x01","changes":[["0x0a","0x0b"],["0x0c",null]]}],"id":1}"#;
+        assert_eq!(
+            parse_storage_changes(resp).unwrap(),
+            vec![(vec![0x0a], Some(vec![0x0b])), (vec![0x0c], None)]
+        );
+        let failed = br#"{"jsonrpc":"2.0","error":{"code":4003,"message":"Client error: unknown block"},"id":1}"#;
+        assert!(matches!(
+            parse_storage_changes(failed),
+            Err(Error::RpcError { code: 4003, .. })
+        ));
+    }
+
+    #[test]
+    fn decode_storage_works() {
+        let value = Some("0x0500000000000000000000000000000000");
+        assert_eq!(decode_storage::<u32>(value).unwrap(), Some(5));
+        assert_eq!(decode_storage::<u128>(None).unwrap(), None);
+        assert!(matches!(
+            decode_storage::<u128>(Some("0x05")),
+            Err(Error::FailedToDecodeStorage)
+        ));
+    }
+
+    #[test]
+    fn rollup_nonce_storage_key_works() {
+        let contract_id = AccountId::from([1u8; 32]);
+        let key = rollup_nonce_storage_key(&contract_id);
+        let prefix = subrpc::storage::storage_prefix("PhatRollupAnchor", "Nonces");
+        assert!(key.starts_with(&prefix));
+        // Blake2_128Concat keeps the anchor name at the end
+        assert!(key.ends_with(&[1u8; 32]));
+        assert_ne!(key, rollup_nonce_storage_key(&AccountId::from([2u8; 32])));
+    }
+}
@@ -4,16 +4,18 @@ extern crate alloc;
 
 use core::fmt::Debug;
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeSet, string::String, vec::Vec};
 use scale::{Decode, Encode};
 
 pub mod clients;
 pub mod lock;
 pub mod platforms;
+pub mod simulator;
 
 #[derive(Debug)]
 pub enum Error {
     UnknownLock,
+    TooManyLocks,
     FailedToReadVersion,
     FailedToDecode,
     DecodeOverflow,
@@ -37,10 +39,43 @@ pub enum Error {
     EvmFailedToGetStorage(pink_web3::contract::Error),
     #[cfg(feature = "evm")]
     EvmFailedToPrepareMetaTx(pink_web3::contract::Error),
+    #[cfg(feature = "evm")]
+    EvmFailedToBatchRead(pink_web3::Error),
+    #[cfg(feature = "evm")]
+    EvmFailedToQueryChain(pink_web3::Error),
+    #[cfg(feature = "evm")]
+    EvmChainIdMismatch,
+    /// The tx may cost more than the spending cap
+    #[cfg(feature = "evm")]
+    EvmFeeOverCap,
+    /// The anchor rejected the tx with a known custom error
+    #[cfg(feature = "evm")]
+    EvmAnchorReverted(clients::evm::AnchorError),
 
     QueueIndexOverflow,
     LockVersionOverflow,
     RpcNetworkError,
+    CondNotMet,
+    InvalidPopTarget,
+    UnsupportedCond,
+    ContradictoryConds,
+    MissingTarget,
+    UnsupportedTarget,
+    UnsupportedSignature,
+    UnsupportedMetaTx,
+    InvalidSignature,
+    InvalidQuorum,
+    /// The RPC endpoints cross validating the reads disagree
+    RpcMismatch,
+    NoRpcEndpoint,
+    /// The same tx was submitted recently, with the tx id of the last submission
+    DuplicateSubmission(Vec<u8>),
+    /// The node answered with a JSON-RPC error
+    RpcError {
+        code: i64,
+        message: String,
+        data: Option<String>,
+    },
 
     #[cfg(feature = "ink")]
     InkFailedToCallContract(subrpc::contracts::Error),
@@ -60,9 +95,340 @@ pub enum Error {
     KVError(kv_session::Error),
     #[cfg(feature = "ink")]
     InvalidAddressLength,
+
+    #[cfg(feature = "substrate")]
+    SubstrateFailedToCreateTransaction(subrpc::traits::common::Error),
+    #[cfg(feature = "substrate")]
+    SubstrateFailedToSendTransaction(subrpc::traits::common::Error),
 }
 pub type Result<T> = core::result::Result<T, Error>;
 
+/// The class of an `Error`, telling the caller how to react to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+pub enum ErrorKind {
+    /// The RPC request failed. Retry later.
+    Network,
+    /// The chain state changed since the snapshot, e.g. a condition is not met or the nonce is
+    /// stale. Retry with a new session.
+    Conflict,
+    /// The submission account can't pay the fee. Back off and top it up.
+    Fee,
+    /// The tx, the data or the config is invalid. Retrying doesn't help.
+    Invalid,
+    /// The target chain failed for a reason not recognized
+    Unknown,
+}
+
+impl ErrorKind {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorKind::Network | ErrorKind::Conflict)
+    }
+}
+
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let s = match self {
+            ErrorKind::Network => "network error",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Fee => "fee error",
+            ErrorKind::Invalid => "invalid",
+            ErrorKind::Unknown => "unknown error",
+        };
+        f.write_str(s)
+    }
+}
+
+impl Error {
+    /// Classifies the error
+    ///
+    /// The errors from the RPC clients are classified by their JSON-RPC error codes, the revert
+    /// data of the anchor and the error variants of the clients. `Unknown` is returned for the
+    /// errors not recognized, which are not retried.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::FailedToReadVersion
+            | Error::FailedToGetStorage
+            | Error::FailedToGetBlockHash
+            | Error::FailedToGetBlockNumber
+            | Error::SessionFailedToGetStorage
+            | Error::RpcNetworkError => ErrorKind::Network,
+            Error::SessionError(e) => session_error_kind(e),
+            Error::CondNotMet | Error::InvalidPopTarget | Error::DuplicateSubmission(_) => {
+                ErrorKind::Conflict
+            }
+            Error::FailedToSendTransaction => ErrorKind::Unknown,
+            Error::RpcError {
+                code,
+                message,
+                data,
+            } => rpc_error_kind(*code, message, data.as_deref()),
+            Error::UnknownLock
+            | Error::TooManyLocks
+            | Error::FailedToDecode
+            | Error::DecodeOverflow
+            | Error::FailedToDecodeStorage
+            | Error::FailedToCreateTransaction
+            | Error::SessionFailedToDecode
+            | Error::QueueIndexOverflow
+            | Error::LockVersionOverflow
+            | Error::UnsupportedCond
+            | Error::ContradictoryConds
+            | Error::MissingTarget
+            | Error::UnsupportedTarget
+            | Error::UnsupportedSignature
+            | Error::UnsupportedMetaTx
+            | Error::InvalidSignature
+            | Error::InvalidQuorum
+            | Error::RpcMismatch
+            | Error::NoRpcEndpoint => ErrorKind::Invalid,
+
+            #[cfg(feature = "evm")]
+            Error::BadEvmAnchorAbi | Error::EvmChainIdMismatch => ErrorKind::Invalid,
+            #[cfg(feature = "evm")]
+            Error::EvmFeeOverCap => ErrorKind::Fee,
+            #[cfg(feature = "evm")]
+            Error::EvmAnchorReverted(e) => e.kind(),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToSubmitTx(e)
+            | Error::EvmFailedToBatchRead(e)
+            | Error::EvmFailedToQueryChain(e) => web3_error_kind(e),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToEstimateGas(e)
+            | Error::EvmFailedToGetStorage(e)
+            | Error::EvmFailedToPrepareMetaTx(e) => contract_error_kind(e),
+
+            // The contract calls of subrpc don't tell the node errors apart
+            #[cfg(feature = "ink")]
+            Error::InkFailedToCallContract(_) | Error::InkFailedToQueryContract(_) => {
+                ErrorKind::Unknown
+            }
+            #[cfg(feature = "ink")]
+            Error::InkFailedToDryRunContract(e)
+            | Error::InkFailedToCreateTransaction(e)
+            | Error::InkFailedToSendTransaction(e) => subrpc_error_kind(e),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToPrepareMetaTx => ErrorKind::Unknown,
+            #[cfg(feature = "ink")]
+            Error::InkFailedToDecode(_) | Error::InvalidAddressLength => ErrorKind::Invalid,
+            #[cfg(feature = "ink")]
+            Error::KVError(e) => session_error_kind(e),
+
+            #[cfg(feature = "substrate")]
+            Error::SubstrateFailedToCreateTransaction(e)
+            | Error::SubstrateFailedToSendTransaction(e) => subrpc_error_kind(e),
+        }
+    }
+
+    /// Returns true if the same operation may succeed when retried, maybe with a new session
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Error::UnknownLock => write!(f, "unknown lock"),
+            Error::TooManyLocks => write!(f, "too many locks in a tx"),
+            Error::FailedToReadVersion => write!(f, "failed to read the lock version"),
+            Error::FailedToDecode => write!(f, "failed to decode"),
+            Error::DecodeOverflow => write!(f, "the decoded value overflows"),
+            Error::FailedToGetStorage => write!(f, "failed to read the anchor storage"),
+            Error::FailedToDecodeStorage => write!(f, "failed to decode the anchor storage"),
+            Error::FailedToGetBlockHash => write!(f, "failed to get the block hash"),
+            Error::FailedToGetBlockNumber => write!(f, "failed to get the block number"),
+            Error::FailedToCreateTransaction => write!(f, "failed to create the tx"),
+            Error::FailedToSendTransaction => write!(f, "failed to send the tx"),
+            Error::SessionError(e) => write!(f, "failed to start the session: {e:?}"),
+            Error::SessionFailedToDecode => write!(f, "failed to decode a value in the session"),
+            Error::SessionFailedToGetStorage => write!(f, "failed to read a value in the session"),
+
+            #[cfg(feature = "evm")]
+            Error::BadEvmAnchorAbi => write!(f, "bad ABI of the EVM anchor"),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToSubmitTx(e) => write!(f, "failed to submit the EVM tx: {e:?}"),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToEstimateGas(e) => write!(f, "failed to estimate the gas: {e:?}"),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToGetStorage(e) => write!(f, "failed to read the EVM anchor: {e:?}"),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToPrepareMetaTx(e) => {
+                write!(f, "failed to prepare the meta-tx: {e:?}")
+            }
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToBatchRead(e) => write!(f, "failed to batch read the anchor: {e:?}"),
+            #[cfg(feature = "evm")]
+            Error::EvmFailedToQueryChain(e) => write!(f, "failed to query the chain: {e:?}"),
+            #[cfg(feature = "evm")]
+            Error::EvmChainIdMismatch => write!(f, "the RPC endpoint is on another chain"),
+            #[cfg(feature = "evm")]
+            Error::EvmFeeOverCap => write!(f, "the tx may cost more than the spending cap"),
+            #[cfg(feature = "evm")]
+            Error::EvmAnchorReverted(e) => write!(f, "the EVM anchor reverted: {e:?}"),
+
+            Error::QueueIndexOverflow => write!(f, "the queue index overflows"),
+            Error::LockVersionOverflow => write!(f, "the lock version overflows"),
+            Error::RpcNetworkError => write!(f, "the RPC request failed"),
+            Error::CondNotMet => write!(f, "a condition of the tx is not met"),
+            Error::InvalidPopTarget => write!(f, "the queue head to set is not in the queue"),
+            Error::UnsupportedCond => write!(f, "the anchor doesn't support the condition"),
+            Error::ContradictoryConds => write!(f, "the conditions of the tx contradict"),
+            Error::MissingTarget => write!(f, "the rollup result has no target"),
+            Error::UnsupportedTarget => write!(f, "the client of the target is not enabled"),
+            Error::UnsupportedSignature => write!(f, "the target doesn't accept signed results"),
+            Error::UnsupportedMetaTx => write!(f, "the anchor doesn't accept meta-txs"),
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::InvalidQuorum => write!(f, "the quorum can't be reached by the endpoints"),
+            Error::RpcMismatch => write!(f, "the RPC endpoints disagree on the anchor state"),
+            Error::NoRpcEndpoint => write!(f, "no RPC endpoint"),
+            Error::DuplicateSubmission(tx_id) => {
+                write!(
+                    f,
+                    "the tx was submitted recently as 0x{}",
+                    hex::encode(tx_id)
+                )
+            }
+            Error::RpcError {
+                code,
+                message,
+                data,
+            } => match data {
+                Some(data) => write!(f, "RPC error {code}: {message} ({data})"),
+                None => write!(f, "RPC error {code}: {message}"),
+            },
+
+            #[cfg(feature = "ink")]
+            Error::InkFailedToCallContract(e) => write!(f, "failed to call the anchor: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToQueryContract(e) => write!(f, "failed to query the anchor: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToDryRunContract(e) => {
+                write!(f, "failed to dry run the anchor: {e:?}")
+            }
+            #[cfg(feature = "ink")]
+            Error::InkFailedToCreateTransaction(e) => write!(f, "failed to create the tx: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToSendTransaction(e) => write!(f, "failed to send the tx: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToPrepareMetaTx => write!(f, "failed to prepare the meta-tx"),
+            #[cfg(feature = "ink")]
+            Error::InkFailedToDecode(e) => write!(f, "failed to decode: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::KVError(e) => write!(f, "rollup session error: {e:?}"),
+            #[cfg(feature = "ink")]
+            Error::InvalidAddressLength => write!(f, "invalid address length"),
+
+            #[cfg(feature = "substrate")]
+            Error::SubstrateFailedToCreateTransaction(e) => {
+                write!(f, "failed to create the tx: {e:?}")
+            }
+            #[cfg(feature = "substrate")]
+            Error::SubstrateFailedToSendTransaction(e) => {
+                write!(f, "failed to send the tx: {e:?}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+fn session_error_kind(error: &kv_session::Error) -> ErrorKind {
+    match error {
+        kv_session::Error::FailedToGetStorage => ErrorKind::Network,
+        kv_session::Error::FailedToDecode => ErrorKind::Invalid,
+    }
+}
+
+/// Classifies a JSON-RPC error by its code
+///
+/// Covers the tx pool errors of Substrate, where the reason of an invalid tx is in `data`, the
+/// tx pool errors of geth (and the compatible nodes), which only differ in `message`, and the
+/// generic codes of JSON-RPC and EIP-1474.
+fn rpc_error_kind(code: i64, message: &str, data: Option<&str>) -> ErrorKind {
+    /// The reasons of the tx pool errors of geth, reported with the code -32000
+    const GETH_TX_ERRORS: &[(&str, ErrorKind)] = &[
+        ("nonce too low", ErrorKind::Conflict),
+        ("already known", ErrorKind::Conflict),
+        ("insufficient funds", ErrorKind::Fee),
+        ("transaction underpriced", ErrorKind::Fee),
+        ("replacement transaction underpriced", ErrorKind::Fee),
+        ("max fee per gas less than block base fee", ErrorKind::Fee),
+    ];
+    match code {
+        // Substrate: invalid tx
+        1010 => match data {
+            Some("Transaction is outdated") => ErrorKind::Conflict,
+            Some("Inability to pay some fees (e.g. account balance too low)") => ErrorKind::Fee,
+            _ => ErrorKind::Invalid,
+        },
+        // Substrate: temporarily banned, already imported, or outbid by a tx with the same nonce
+        1012..=1014 => ErrorKind::Conflict,
+        // Substrate: dropped by the full pool. EIP-1474: rate limited.
+        1016 | -32005 => ErrorKind::Network,
+        // JSON-RPC: parse error, invalid request, method not found or invalid params
+        -32700 | -32600 | -32601 | -32602 => ErrorKind::Invalid,
+        -32000 => GETH_TX_ERRORS
+            .iter()
+            .find(|(reason, _)| message.starts_with(reason))
+            .map_or(ErrorKind::Unknown, |(_, kind)| *kind),
+        _ => ErrorKind::Unknown,
+    }
+}
+
+/// Classifies a failed HTTP request by its status code
+#[cfg(feature = "evm")]
+fn http_status_kind(status: u16) -> ErrorKind {
+    match status {
+        408 | 429 | 500..=599 => ErrorKind::Network,
+        400..=499 => ErrorKind::Invalid,
+        _ => ErrorKind::Unknown,
+    }
+}
+
+#[cfg(feature = "evm")]
+fn web3_error_kind(error: &pink_web3::Error) -> ErrorKind {
+    use pink_web3::{error::TransportError, Error as Web3Error};
+    match error {
+        Web3Error::Unreachable | Web3Error::Transport(TransportError::Message(_)) => {
+            ErrorKind::Network
+        }
+        Web3Error::Transport(TransportError::Code(status)) => http_status_kind(*status),
+        Web3Error::Rpc(e) => {
+            let data = e.data.as_ref().and_then(|data| data.as_str());
+            if let Some(reverted) = data.and_then(clients::evm::AnchorError::from_revert_data) {
+                return reverted.kind();
+            }
+            rpc_error_kind(e.code.code(), &e.message, data)
+        }
+        Web3Error::Decoder(_) => ErrorKind::Invalid,
+        _ => ErrorKind::Unknown,
+    }
+}
+
+#[cfg(feature = "evm")]
+fn contract_error_kind(error: &pink_web3::contract::Error) -> ErrorKind {
+    use pink_web3::contract::Error as ContractError;
+    match error {
+        ContractError::Api(e) => web3_error_kind(e),
+        ContractError::Abi(_) | ContractError::InvalidOutputType(_) => ErrorKind::Invalid,
+        _ => ErrorKind::Unknown,
+    }
+}
+
+/// Classifies an error of subrpc
+///
+/// subrpc drops the JSON-RPC error of a failed request, so only the failed HTTP requests are
+/// told apart.
+#[cfg(any(feature = "substrate", feature = "ink"))]
+fn subrpc_error_kind(error: &subrpc::traits::common::Error) -> ErrorKind {
+    match error {
+        subrpc::traits::common::Error::RequestFailed => ErrorKind::Network,
+        _ => ErrorKind::Unknown,
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Encode, Decode)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub struct Raw(Vec<u8>);
@@ -87,7 +453,54 @@ impl From<Raw> for Vec<u8> {
 pub struct RollupResult {
     pub tx: RollupTx,
     pub signature: Option<Vec<u8>>,
-    pub target: Option<Vec<u8>>,
+    /// The anchor to submit the tx to, or `None` to leave it to the submitter
+    pub target: Option<RollupTarget>,
+}
+
+/// The rollup anchor a `RollupResult` is submitted to
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+#[cfg_attr(
+    feature = "std",
+    derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+)]
+pub enum RollupTarget {
+    /// The EVM anchor contract
+    Evm { rpc: String, contract_id: [u8; 20] },
+    /// The Substrate rollup pallet, where `contract_id` is the owner of the rollup states
+    Substrate {
+        rpc: String,
+        pallet_id: u8,
+        contract_id: [u8; 32],
+    },
+    /// The ink! anchor contract
+    Ink {
+        rpc: String,
+        pallet_id: u8,
+        call_id: u8,
+        contract_id: [u8; 32],
+    },
+}
+
+impl RollupResult {
+    /// Signs the tx with the attestor key for the ink! anchor in `target`
+    ///
+    /// The actions of the tx must be SCALE encoded `Action`s. Any relayer can submit the signed
+    /// result by `RollupAnchor::rollup_signed` without the attestor role. The signature doesn't
+    /// stop the same tx from being submitted twice, so the tx must be conditioned on a value it
+    /// updates (see `InkRollupClient::commit_signed()`).
+    ///
+    /// Fails with `Error::UnsupportedSignature` if the target is not an ink! anchor.
+    #[cfg(feature = "pink-extension")]
+    pub fn sign(&mut self, attestor_key: &[u8; 32]) -> Result<()> {
+        use pink_extension::chain_extension::signing;
+        let Some(RollupTarget::Ink { contract_id, .. }) = &self.target else {
+            return Err(Error::UnsupportedSignature);
+        };
+        let hash = self.tx.signing_hash(contract_id);
+        let signature = signing::ecdsa_sign_prehashed(attestor_key, hash);
+        self.signature = Some(signature.to_vec());
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, PartialEq, Eq, Encode, Decode)]
@@ -103,6 +516,72 @@ impl RollupTx {
         self.actions.push(Into::<Vec<u8>>::into(act).into());
         self
     }
+
+    /// The canonical hash of the tx, i.e. blake2b-256 of its SCALE encoding
+    pub fn hash(&self) -> [u8; 32] {
+        blake2_256_encoded(self)
+    }
+
+    /// The message signed by the attestor to deliver the tx to the ink! anchor `anchor`
+    ///
+    /// It's blake2b-256 of the SCALE encoded `(anchor, tx)`, which is what the anchor hashes
+    /// in `RollupAnchor::rollup_signed`. Binding the anchor id prevents the signature from being
+    /// replayed to another anchor.
+    pub fn signing_hash(&self, anchor: &[u8; 32]) -> [u8; 32] {
+        blake2_256_encoded(&(anchor, self))
+    }
+
+    /// Removes the redundant entries of the tx
+    ///
+    /// Duplicate conditions are merged, and only the last write to each key is kept. Fails with
+    /// `Error::ContradictoryConds` if the conditions can never be met at the same time, because
+    /// such a tx will always be rejected by the anchor.
+    pub fn normalize(&mut self) -> Result<()> {
+        normalize_conds(&mut self.conds)?;
+        normalize_updates(&mut self.updates);
+        Ok(())
+    }
+}
+
+fn normalize_conds(conds: &mut Vec<Cond>) -> Result<()> {
+    let mut merged: Vec<Cond> = Vec::with_capacity(conds.len());
+    for cond in conds.drain(..) {
+        let mut redundant = false;
+        for prev in merged.iter_mut().filter(|prev| prev.key() == cond.key()) {
+            if prev.conflicts(&cond) {
+                return Err(Error::ContradictoryConds);
+            }
+            // Keep the strictest bound
+            match (prev, &cond) {
+                (prev, cond) if prev == cond => redundant = true,
+                (Cond::Le(_, a), Cond::Le(_, b)) => {
+                    *a = (*a).min(*b);
+                    redundant = true;
+                }
+                (Cond::Ge(_, a), Cond::Ge(_, b)) => {
+                    *a = (*a).max(*b);
+                    redundant = true;
+                }
+                _ => (),
+            }
+        }
+        if !redundant {
+            merged.push(cond);
+        }
+    }
+    *conds = merged;
+    Ok(())
+}
+
+fn normalize_updates(updates: &mut Vec<(Raw, Option<Raw>)>) {
+    let mut written = BTreeSet::new();
+    let mut last_writes: Vec<_> = updates
+        .drain(..)
+        .rev()
+        .filter(|(key, _)| written.insert(key.0.clone()))
+        .collect();
+    last_writes.reverse();
+    *updates = last_writes;
 }
 
 #[derive(Debug, PartialEq, Eq, Encode, Decode)]
@@ -115,7 +594,65 @@ pub enum Action {
 #[derive(Debug, PartialEq, Eq, Encode, Decode)]
 #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
 pub enum Cond {
+    /// The value equals to the expected one (`None` for absence)
     Eq(Raw, Option<Raw>),
+    /// The value doesn't equal to the given one (`None` for absence)
+    Ne(Raw, Option<Raw>),
+    /// The key exists
+    Exists(Raw),
+    /// The key doesn't exist
+    NotExists(Raw),
+    /// The version (or queue index) stored at the key is less than or equal to the number
+    ///
+    /// An absent value is treated as 0.
+    Le(Raw, u32),
+    /// The version (or queue index) stored at the key is greater than or equal to the number
+    ///
+    /// An absent value is treated as 0.
+    Ge(Raw, u32),
+}
+
+impl Cond {
+    /// Returns the key the condition applies to
+    pub fn key(&self) -> &Raw {
+        match self {
+            Cond::Eq(k, _)
+            | Cond::Ne(k, _)
+            | Cond::Exists(k)
+            | Cond::NotExists(k)
+            | Cond::Le(k, _)
+            | Cond::Ge(k, _) => k,
+        }
+    }
+
+    /// Returns true if the two conditions on the same key can never be met at the same time
+    ///
+    /// The numeric conditions are not compared with `Eq` and `Ne` because the encoding of the
+    /// value depends on the platform.
+    pub fn conflicts(&self, other: &Cond) -> bool {
+        use Cond::*;
+        match (self, other) {
+            (Eq(_, a), Eq(_, b)) => a != b,
+            (Eq(_, a), Ne(_, b)) | (Ne(_, b), Eq(_, a)) => a == b,
+            (Eq(_, v), Exists(_)) | (Exists(_), Eq(_, v)) => v.is_none(),
+            (Eq(_, v), NotExists(_)) | (NotExists(_), Eq(_, v)) => v.is_some(),
+            (Ne(_, None), NotExists(_)) | (NotExists(_), Ne(_, None)) => true,
+            (Exists(_), NotExists(_)) | (NotExists(_), Exists(_)) => true,
+            (Le(_, max), Ge(_, min)) | (Ge(_, min), Le(_, max)) => min > max,
+            // An absent value is treated as 0
+            (Ge(_, min), NotExists(_) | Eq(_, None)) | (NotExists(_) | Eq(_, None), Ge(_, min)) => {
+                *min > 0
+            }
+            _ => false,
+        }
+    }
+}
+
+fn blake2_256_encoded(data: &impl Encode) -> [u8; 32] {
+    use ink::env::hash::{Blake2x256, HashOutput};
+    let mut output = <Blake2x256 as HashOutput>::Type::default();
+    ink::env::hash_encoded::<Blake2x256, _>(data, &mut output);
+    output
 }
 
 #[ink::trait_definition]
@@ -133,9 +670,183 @@ pub type RollupHandlerForwarder<Env> = <
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use alloc::vec;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    fn raw(data: &[u8]) -> Raw {
+        data.to_vec().into()
+    }
+
+    #[test]
+    fn normalize_works() {
+        let mut tx = RollupTx {
+            conds: vec![
+                Cond::Eq(raw(b"a"), Some(raw(b"1"))),
+                Cond::Le(raw(b"b"), 10),
+                Cond::Eq(raw(b"a"), Some(raw(b"1"))),
+                Cond::Le(raw(b"b"), 5),
+                Cond::Ge(raw(b"b"), 1),
+                Cond::Ge(raw(b"b"), 2),
+                Cond::NotExists(raw(b"c")),
+            ],
+            actions: vec![raw(b"act")],
+            updates: vec![
+                (raw(b"a"), Some(raw(b"2"))),
+                (raw(b"b"), None),
+                (raw(b"a"), Some(raw(b"3"))),
+            ],
+        };
+        tx.normalize().expect("tx should be valid");
+        assert_eq!(
+            tx,
+            RollupTx {
+                conds: vec![
+                    Cond::Eq(raw(b"a"), Some(raw(b"1"))),
+                    Cond::Le(raw(b"b"), 5),
+                    Cond::Ge(raw(b"b"), 2),
+                    Cond::NotExists(raw(b"c")),
+                ],
+                actions: vec![raw(b"act")],
+                updates: vec![(raw(b"b"), None), (raw(b"a"), Some(raw(b"3")))],
+            }
+        );
+    }
+
+    #[test]
+    fn normalize_rejects_contradictions() {
+        let contradictions = [
+            (
+                Cond::Eq(raw(b"k"), Some(raw(b"1"))),
+                Cond::Eq(raw(b"k"), None),
+            ),
+            (Cond::Eq(raw(b"k"), None), Cond::Ne(raw(b"k"), None)),
+            (Cond::Eq(raw(b"k"), None), Cond::Exists(raw(b"k"))),
+            (
+                Cond::NotExists(raw(b"k")),
+                Cond::Eq(raw(b"k"), Some(raw(b"1"))),
+            ),
+            (Cond::Exists(raw(b"k")), Cond::NotExists(raw(b"k"))),
+            (Cond::Ge(raw(b"k"), 3), Cond::Le(raw(b"k"), 2)),
+            (Cond::NotExists(raw(b"k")), Cond::Ge(raw(b"k"), 1)),
+        ];
+        for (a, b) in contradictions {
+            let mut tx = RollupTx {
+                conds: vec![a, b],
+                ..Default::default()
+            };
+            assert!(matches!(tx.normalize(), Err(Error::ContradictoryConds)));
+        }
+
+        // Conditions on different keys never conflict
+        let mut tx = RollupTx {
+            conds: vec![Cond::Exists(raw(b"a")), Cond::NotExists(raw(b"b"))],
+            ..Default::default()
+        };
+        assert!(tx.normalize().is_ok());
+    }
+
+    #[test]
+    fn signing_hash_works() {
+        let mut tx = RollupTx {
+            conds: vec![Cond::Eq(raw(b"k"), None)],
+            updates: vec![(raw(b"k"), Some(raw(b"v")))],
+            ..Default::default()
+        };
+        tx.action(Action::ProcessedTo(1).encode());
+
+        // The anchor hashes its own id followed by the encoded tx
+        let anchor = [1u8; 32];
+        let message = [&anchor[..], &tx.encode()].concat();
+        let mut expected = [0u8; 32];
+        ink::env::hash_bytes::<ink::env::hash::Blake2x256>(&message, &mut expected);
+        assert_eq!(tx.signing_hash(&anchor), expected);
+        assert_ne!(tx.signing_hash(&anchor), tx.signing_hash(&[2u8; 32]));
+        assert_eq!(tx.hash(), blake2_256_encoded(&tx));
+    }
+
+    #[test]
+    fn error_kind_works() {
+        assert_eq!(Error::CondNotMet.kind(), ErrorKind::Conflict);
+        assert!(Error::CondNotMet.is_retryable());
+        assert!(Error::SessionFailedToGetStorage.is_retryable());
+        assert!(!Error::ContradictoryConds.is_retryable());
+        // A disagreement of the endpoints doesn't go away by retrying
+        assert!(!Error::RpcMismatch.is_retryable());
+        assert_eq!(
+            Error::SessionError(kv_session::Error::FailedToDecode).kind(),
+            ErrorKind::Invalid
+        );
+        assert_eq!(
+            alloc::format!("{}", Error::CondNotMet),
+            "a condition of the tx is not met"
+        );
+
+        let rpc_error = |code, message: &str, data: Option<&str>| Error::RpcError {
+            code,
+            message: message.into(),
+            data: data.map(Into::into),
+        };
+        // Substrate
+        let outdated = rpc_error(1010, "Invalid Transaction", Some("Transaction is outdated"));
+        assert_eq!(outdated.kind(), ErrorKind::Conflict);
+        assert_eq!(
+            alloc::format!("{outdated}"),
+            "RPC error 1010: Invalid Transaction (Transaction is outdated)"
+        );
+        assert_eq!(
+            rpc_error(
+                1010,
+                "Invalid Transaction",
+                Some("Inability to pay some fees (e.g. account balance too low)")
+            )
+            .kind(),
+            ErrorKind::Fee
+        );
+        assert_eq!(
+            rpc_error(
+                1010,
+                "Invalid Transaction",
+                Some("Transaction has a bad signature")
+            )
+            .kind(),
+            ErrorKind::Invalid
+        );
+        assert_eq!(
+            rpc_error(1014, "Priority is too low", None).kind(),
+            ErrorKind::Conflict
+        );
+        // geth
+        assert_eq!(
+            rpc_error(-32000, "nonce too low: next nonce 5, tx nonce 4", None).kind(),
+            ErrorKind::Conflict
+        );
+        assert_eq!(
+            rpc_error(-32000, "insufficient funds for gas * price + value", None).kind(),
+            ErrorKind::Fee
+        );
+        assert_eq!(
+            rpc_error(-32005, "limit exceeded", None).kind(),
+            ErrorKind::Network
+        );
+        assert_eq!(
+            rpc_error(-32601, "the method does not exist", None).kind(),
+            ErrorKind::Invalid
+        );
+        // Not recognized, e.g. a revert not from the anchor, or a message merely mentioning a fee
+        assert_eq!(
+            rpc_error(3, "execution reverted", Some("0x1234")).kind(),
+            ErrorKind::Unknown
+        );
+        assert_eq!(
+            rpc_error(-32000, "fee cap exceeded at http://node", None).kind(),
+            ErrorKind::Unknown
+        );
+        assert!(!rpc_error(-32603, "internal error", None).is_retryable());
+    }
 }
@@ -2,9 +2,12 @@
 use crate::{platforms::Platform, Cond, Error, Raw, Result, RollupTx};
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     string::{String, ToString},
+    vec::Vec,
 };
+use core::marker::PhantomData;
+use kv_session::traits::KvSnapshot;
 
 pub const GLOBAL: &str = "Global";
 
@@ -12,10 +15,11 @@ pub type LockId = u8;
 pub type LockVersion = u32;
 
 pub struct Locks<P: Platform> {
-    num_locks: LockId,
+    num_locks: u16,
     lock_ids: BTreeMap<String, LockId>,
     deps: BTreeMap<LockId, LockId>,
-    _mark: core::marker::PhantomData<P>,
+    families: BTreeSet<LockId>,
+    _mark: PhantomData<P>,
 }
 
 impl<P: Platform> Default for Locks<P> {
@@ -26,6 +30,7 @@ impl<P: Platform> Default for Locks<P> {
             num_locks: 1,
             lock_ids,
             deps: BTreeMap::new(),
+            families: BTreeSet::new(),
             _mark: Default::default(),
         }
     }
@@ -34,47 +39,118 @@ impl<P: Platform> Default for Locks<P> {
 impl<P: Platform> Locks<P> {
     pub fn add(&mut self, lock: &str, parent: &str) -> Result<LockId> {
         let parent_id = *self.lock_ids.get(parent).ok_or(Error::UnknownLock)?;
-        let id = self.num_locks;
+        let id = LockId::try_from(self.num_locks).or(Err(Error::TooManyLocks))?;
+        self.num_locks += 1;
         self.lock_ids.insert(lock.to_string(), id);
         self.deps.insert(id, parent_id);
-        self.num_locks += 1;
         Ok(id)
     }
 
-    // TODO: support parameterized lock
-    // TODO: dedup tx entries
+    /// Adds a family of parameterized locks (e.g. `balance/{account}`)
+    ///
+    /// A lock in the family is created on demand for each parameter. The family itself is a lock
+    /// under `parent`, and it's the parent of all the locks in the family. So reading the family
+    /// conflicts with writing any of its members.
+    pub fn add_family(&mut self, family: &str, parent: &str) -> Result<LockId> {
+        let id = self.add(family, parent)?;
+        self.families.insert(id);
+        Ok(id)
+    }
+
+    /// Resolves a read access to the lock
+    pub fn read(&self, lock: &str) -> Result<LockAccess<P>> {
+        let id = self.lock_id(lock)?;
+        Ok(LockAccess::new(Self::key(id), Vec::new()))
+    }
+
+    /// Resolves a read access to the lock of `param` in the family
+    pub fn read_param(&self, family: &str, param: &[u8]) -> Result<LockAccess<P>> {
+        let id = self.family_id(family)?;
+        Ok(LockAccess::new(Self::param_key(id, param), Vec::new()))
+    }
 
+    /// Resolves a write access to the lock
+    pub fn write(&self, lock: &str) -> Result<LockAccess<P>> {
+        let id = self.lock_id(lock)?;
+        let key = Self::key(id);
+        let bumps = self.path(key.clone(), self.deps.get(&id).cloned());
+        Ok(LockAccess::new(key, bumps))
+    }
+
+    /// Resolves a write access to the lock of `param` in the family
+    pub fn write_param(&self, family: &str, param: &[u8]) -> Result<LockAccess<P>> {
+        let id = self.family_id(family)?;
+        let key = Self::param_key(id, param);
+        let bumps = self.path(key.clone(), Some(id));
+        Ok(LockAccess::new(key, bumps))
+    }
+
+    /// Adds the version check of the lock to the tx
+    ///
+    /// The entries of multiple locks may overlap. Call `RollupTx::normalize()` to remove the
+    /// duplicates before submission.
     pub fn tx_read(
         &self,
         tx: &mut RollupTx,
         reader: &impl LockVersionReader,
         lock: &str,
     ) -> Result<()> {
-        let id = *self.lock_ids.get(lock).ok_or(Error::UnknownLock)?;
-        // Only check version
-        let v = reader.get_version(id)?;
-        tx.conds.push(Cond::Eq(Self::key(id), Some(Self::value(v))));
-        Ok(())
+        self.read(lock)?.apply(tx, reader)
     }
 
+    /// Adds the version check of the lock to the tx
+    pub fn tx_read_param(
+        &self,
+        tx: &mut RollupTx,
+        reader: &impl LockVersionReader,
+        family: &str,
+        param: &[u8],
+    ) -> Result<()> {
+        self.read_param(family, param)?.apply(tx, reader)
+    }
+
+    /// Adds the version check of the lock, and bumps the versions of the lock and its ancestors
     pub fn tx_write(
         &self,
         tx: &mut RollupTx,
         reader: &impl LockVersionReader,
         lock: &str,
     ) -> Result<()> {
-        let id = *self.lock_ids.get(lock).ok_or(Error::UnknownLock)?;
-        // Check reading version
-        let v = reader.get_version(id)?;
-        tx.conds.push(Cond::Eq(Self::key(id), Some(Self::value(v))));
-        // Update writing versions
-        let mut i = Some(id);
+        self.write(lock)?.apply(tx, reader)
+    }
+
+    /// Adds the version check of the lock, and bumps the versions of the lock and its ancestors
+    pub fn tx_write_param(
+        &self,
+        tx: &mut RollupTx,
+        reader: &impl LockVersionReader,
+        family: &str,
+        param: &[u8],
+    ) -> Result<()> {
+        self.write_param(family, param)?.apply(tx, reader)
+    }
+
+    fn lock_id(&self, lock: &str) -> Result<LockId> {
+        self.lock_ids.get(lock).cloned().ok_or(Error::UnknownLock)
+    }
+
+    fn family_id(&self, family: &str) -> Result<LockId> {
+        let id = self.lock_id(family)?;
+        if !self.families.contains(&id) {
+            return Err(Error::UnknownLock);
+        }
+        Ok(id)
+    }
+
+    /// Returns the key of the lock followed by the keys of its ancestors
+    fn path(&self, key: Raw, parent: Option<LockId>) -> Vec<Raw> {
+        let mut path = alloc::vec![key];
+        let mut i = parent;
         while let Some(id) = i {
-            let v = reader.get_version(id)?;
-            tx.updates.push((Self::key(id), Some(Self::value(v + 1))));
+            path.push(Self::key(id));
             i = self.deps.get(&id).cloned();
         }
-        Ok(())
+        path
     }
 
     pub fn key(id: LockId) -> Raw {
@@ -82,28 +158,97 @@ impl<P: Platform> Locks<P> {
         vec![id].into()
     }
 
+    /// The key of the lock in a parameterized family: `{id}/{param}`
+    pub fn param_key(id: LockId, param: &[u8]) -> Raw {
+        let mut key = Vec::with_capacity(param.len() + 2);
+        key.push(id);
+        key.push(b'/');
+        key.extend_from_slice(param);
+        key.into()
+    }
+
     pub fn value(version: LockVersion) -> Raw {
         P::encode_u32(version).into()
     }
 }
 
+/// A lock access resolved by `Locks`, ready to apply to a rollup tx
+///
+/// It can be passed to `RollupClient::lock()` to acquire the lock in a rollup session.
+pub struct LockAccess<P: Platform> {
+    /// The lock to check the version
+    key: Raw,
+    /// The locks to bump the versions. Empty for a read access.
+    bumps: Vec<Raw>,
+    _mark: PhantomData<P>,
+}
+
+impl<P: Platform> LockAccess<P> {
+    fn new(key: Raw, bumps: Vec<Raw>) -> Self {
+        Self {
+            key,
+            bumps,
+            _mark: PhantomData,
+        }
+    }
+
+    /// Returns true if the access bumps the lock versions
+    pub fn is_write(&self) -> bool {
+        !self.bumps.is_empty()
+    }
+
+    /// Adds the version check and the version bumps to the tx
+    pub fn apply(&self, tx: &mut RollupTx, reader: &impl LockVersionReader) -> Result<()> {
+        // Check reading version
+        let v = reader.get_version(&self.key.0)?;
+        tx.conds.push(Cond::Eq(
+            self.key.clone(),
+            P::expected_u32(v).map(Into::into),
+        ));
+        // Update writing versions
+        for key in &self.bumps {
+            let v = reader.get_version(&key.0)?;
+            let new = v.checked_add(1).ok_or(Error::LockVersionOverflow)?;
+            tx.updates.push((key.clone(), Some(Locks::<P>::value(new))));
+        }
+        Ok(())
+    }
+}
+
 pub trait LockVersionReader {
-    fn get_version(&self, id: LockId) -> Result<LockVersion>;
+    /// Reads the version of the lock at `key`. An absent lock has version 0.
+    fn get_version(&self, key: &[u8]) -> Result<LockVersion>;
+}
+
+/// Reads the lock version from a rollup snapshot
+///
+/// Used by the snapshots to implement `LockVersionReader`.
+pub(crate) fn read_version<P: Platform>(
+    snapshot: &impl KvSnapshot,
+    key: &[u8],
+) -> Result<LockVersion> {
+    match snapshot.get(key).or(Err(Error::FailedToReadVersion))? {
+        Some(v) => P::decode_u32(&v),
+        None => Ok(0),
+    }
 }
 
 pub type EvmLocks = Locks<crate::platforms::Evm>;
+pub type SubstrateLocks = Locks<crate::platforms::Substrate>;
+pub type InkLocks = Locks<crate::platforms::Ink>;
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use scale::Encode;
 
     #[derive(Default)]
     struct MockVersionStore {
-        versions: BTreeMap<LockId, LockVersion>,
+        versions: BTreeMap<Vec<u8>, LockVersion>,
     }
     impl LockVersionReader for MockVersionStore {
-        fn get_version(&self, id: LockId) -> Result<LockVersion> {
-            Ok(self.versions.get(&id).cloned().unwrap_or(0))
+        fn get_version(&self, key: &[u8]) -> Result<LockVersion> {
+            Ok(self.versions.get(key).cloned().unwrap_or(0))
         }
     }
 
@@ -145,5 +290,117 @@ mod test {
                 ],
             }
         );
+
+        // Overlapping locks are deduplicated
+        let mut tx = RollupTx::default();
+        locks.tx_read(&mut tx, &vstore, "a").unwrap();
+        locks.tx_write(&mut tx, &vstore, "a").unwrap();
+        locks.tx_write(&mut tx, &vstore, "b").unwrap();
+        tx.normalize().expect("locks should not conflict");
+        assert_eq!(
+            tx,
+            RollupTx {
+                conds: vec![
+                    Cond::Eq(EvmLocks::key(1), Some(EvmLocks::value(0))),
+                    Cond::Eq(EvmLocks::key(2), Some(EvmLocks::value(0))),
+                ],
+                actions: vec![],
+                updates: vec![
+                    (EvmLocks::key(2), Some(EvmLocks::value(1))),
+                    (EvmLocks::key(1), Some(EvmLocks::value(1))),
+                    (EvmLocks::key(0), Some(EvmLocks::value(1))),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parameterized_lock_works() {
+        let mut locks = EvmLocks::default();
+        let family = locks.add_family("balance", GLOBAL).unwrap();
+        locks.add("a", GLOBAL).unwrap();
+
+        let mut vstore = MockVersionStore::default();
+        let alice = EvmLocks::param_key(family, b"alice");
+        vstore.versions.insert(alice.clone().into(), 3);
+
+        // Write a lock in the family
+        let mut tx = RollupTx::default();
+        locks
+            .tx_write_param(&mut tx, &vstore, "balance", b"alice")
+            .expect("write should succeed");
+        assert_eq!(
+            tx,
+            RollupTx {
+                conds: vec![Cond::Eq(alice.clone(), Some(EvmLocks::value(3)))],
+                actions: vec![],
+                updates: vec![
+                    (alice, Some(EvmLocks::value(4))),
+                    (EvmLocks::key(family), Some(EvmLocks::value(1))),
+                    (EvmLocks::key(0), Some(EvmLocks::value(1))),
+                ],
+            }
+        );
+
+        // Each parameter gets its own lock
+        let mut tx = RollupTx::default();
+        locks
+            .tx_read_param(&mut tx, &vstore, "balance", b"bob")
+            .expect("read should succeed");
+        assert_eq!(
+            tx.conds,
+            vec![Cond::Eq(
+                EvmLocks::param_key(family, b"bob"),
+                Some(EvmLocks::value(0))
+            )]
+        );
+
+        // Only families accept parameters
+        assert!(matches!(
+            locks.tx_read_param(&mut tx, &vstore, "a", b"bob"),
+            Err(Error::UnknownLock)
+        ));
+        assert!(matches!(
+            locks.tx_write_param(&mut tx, &vstore, "unknown", b"bob"),
+            Err(Error::UnknownLock)
+        ));
+    }
+
+    #[test]
+    fn scale_lock_works() {
+        let mut locks = InkLocks::default();
+        locks.add("a", GLOBAL).unwrap();
+
+        let mut vstore = MockVersionStore::default();
+        vstore.versions.insert(InkLocks::key(0).into(), 1);
+
+        // The version 0 is never written to the SCALE kv store
+        let mut tx = RollupTx::default();
+        locks
+            .tx_write(&mut tx, &vstore, "a")
+            .expect("write should succeed");
+        assert_eq!(
+            tx,
+            RollupTx {
+                conds: vec![Cond::Eq(InkLocks::key(1), None)],
+                actions: vec![],
+                updates: vec![
+                    (InkLocks::key(1), Some(1u32.encode().into())),
+                    (InkLocks::key(0), Some(2u32.encode().into())),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn too_many_locks() {
+        let mut locks = EvmLocks::default();
+        for i in 1..=LockId::MAX {
+            assert_eq!(locks.add(&i.to_string(), GLOBAL).unwrap(), i);
+        }
+        assert!(matches!(
+            locks.add("overflow", GLOBAL),
+            Err(Error::TooManyLocks)
+        ));
     }
 }
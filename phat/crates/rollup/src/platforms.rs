@@ -1,6 +1,9 @@
 use crate::{Error, Result};
 use alloc::vec::Vec;
+use core::marker::PhantomData;
+use kv_session::traits::QueueIndexCodec;
 use primitive_types::U256;
+use scale::{Decode, Encode};
 
 pub trait Platform {
     fn encode_u32(n: u32) -> Vec<u8>;
@@ -8,6 +11,23 @@ pub trait Platform {
 
     fn encode_u256(n: U256) -> Vec<u8>;
     fn decode_u256(data: &[u8]) -> Result<U256>;
+
+    /// Returns the value expected in the kv store for a version (or queue index)
+    ///
+    /// It's used to check the version in a condition. `None` means the value is absent.
+    fn expected_u32(n: u32) -> Option<Vec<u8>> {
+        Some(Self::encode_u32(n))
+    }
+
+    /// Increments the version stored in the kv store (`None` for absence)
+    fn bump_version(version: Option<&[u8]>) -> Result<Vec<u8>> {
+        let old = match version {
+            Some(v) => Self::decode_u32(v)?,
+            None => 0,
+        };
+        let new = old.checked_add(1).ok_or(Error::LockVersionOverflow)?;
+        Ok(Self::encode_u32(new))
+    }
 }
 
 pub struct Evm;
@@ -24,15 +44,122 @@ impl Platform for Evm {
         u256_be(n).to_vec()
     }
     fn decode_u256(data: &[u8]) -> Result<U256> {
-        if data.len() < 32 {
+        // EVM contract always returns the raw bytes. Even if the storage value doesn't exist, it
+        // returns a zero length bytes array, which is treated as 0.
+        if data.is_empty() {
+            return Ok(U256::zero());
+        }
+        if data.len() != 32 {
             return Err(Error::FailedToDecode);
         }
         Ok(U256::from_big_endian(data))
     }
 }
 
+/// Substrate pallet anchor with SCALE encoded values
+pub struct Substrate;
+impl Platform for Substrate {
+    fn encode_u32(n: u32) -> Vec<u8> {
+        n.encode()
+    }
+    fn decode_u32(data: &[u8]) -> Result<u32> {
+        // An absent value is not a zero length bytes array in the SCALE kv store, so `[]` is not
+        // considered here.
+        Decode::decode(&mut &data[..]).or(Err(Error::FailedToDecode))
+    }
+
+    fn encode_u256(n: U256) -> Vec<u8> {
+        n.encode()
+    }
+    fn decode_u256(data: &[u8]) -> Result<U256> {
+        Decode::decode(&mut &data[..]).or(Err(Error::FailedToDecode))
+    }
+
+    fn expected_u32(n: u32) -> Option<Vec<u8>> {
+        // The versions start from 1 when they are first written
+        (n != 0).then(|| Self::encode_u32(n))
+    }
+}
+
+/// ink! anchor contract with SCALE encoded values
+pub struct Ink;
+impl Platform for Ink {
+    fn encode_u32(n: u32) -> Vec<u8> {
+        Substrate::encode_u32(n)
+    }
+    fn decode_u32(data: &[u8]) -> Result<u32> {
+        Substrate::decode_u32(data)
+    }
+
+    fn encode_u256(n: U256) -> Vec<u8> {
+        Substrate::encode_u256(n)
+    }
+    fn decode_u256(data: &[u8]) -> Result<U256> {
+        Substrate::decode_u256(data)
+    }
+
+    fn expected_u32(n: u32) -> Option<Vec<u8>> {
+        Substrate::expected_u32(n)
+    }
+}
+
+/// The queue index codec of a platform, used by the rollup session
+pub struct PlatformCodec<P: Platform>(PhantomData<P>);
+impl<P: Platform> QueueIndexCodec for PlatformCodec<P> {
+    fn encode(number: u32) -> Vec<u8> {
+        P::encode_u32(number)
+    }
+
+    fn decode(raw: impl AsRef<[u8]>) -> kv_session::Result<u32> {
+        P::decode_u32(raw.as_ref()).or(Err(kv_session::Error::FailedToDecode))
+    }
+}
+
 fn u256_be(n: U256) -> [u8; 32] {
     let mut r = [0u8; 32];
     n.to_big_endian(&mut r);
     r
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evm_works() {
+        assert_eq!(Evm::decode_u32(&Evm::encode_u32(42)).unwrap(), 42);
+        assert_eq!(Evm::decode_u32(&[]).unwrap(), 0);
+        assert!(matches!(Evm::decode_u32(&[1]), Err(Error::FailedToDecode)));
+        assert!(matches!(
+            Evm::decode_u32(&Evm::encode_u256(U256::MAX)),
+            Err(Error::DecodeOverflow)
+        ));
+        assert_eq!(Evm::bump_version(None).unwrap(), Evm::encode_u32(1));
+        assert_eq!(Evm::expected_u32(0), Some(Evm::encode_u32(0)));
+    }
+
+    #[test]
+    fn scale_works() {
+        assert_eq!(Ink::encode_u32(42), 42u32.encode());
+        assert_eq!(Ink::decode_u32(&42u32.encode()).unwrap(), 42);
+        assert_eq!(
+            Substrate::decode_u256(&U256::MAX.encode()).unwrap(),
+            U256::MAX
+        );
+        assert!(matches!(
+            Substrate::decode_u32(&[]),
+            Err(Error::FailedToDecode)
+        ));
+        assert_eq!(Substrate::bump_version(None).unwrap(), 1u32.encode());
+        assert_eq!(
+            Substrate::bump_version(Some(&1u32.encode())).unwrap(),
+            2u32.encode()
+        );
+        assert!(matches!(
+            Substrate::bump_version(Some(&u32::MAX.encode())),
+            Err(Error::LockVersionOverflow)
+        ));
+        assert_eq!(Ink::expected_u32(0), None);
+        assert_eq!(Ink::expected_u32(1), Some(1u32.encode()));
+    }
+}
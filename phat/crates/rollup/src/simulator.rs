@@ -0,0 +1,654 @@
+//! In-memory rollup anchor for offline testing
+//!
+//! `MemoryAnchor` keeps the rollup kv store and the request queue in memory, and applies a
+//! `RollupTx` in the same way as the anchor contracts do. `MemoryRollupClient` runs a rollup
+//! session against it, so the business logic of a Phat Contract can be tested end to end in
+//! `cargo test` without a live node.
+use crate::{
+    clients::{
+        split::{self, ActionLog, TxLimit},
+        RollupClient, Submittable,
+    },
+    lock::{self, LockAccess, LockVersion, LockVersionReader},
+    platforms::{Ink, Platform, PlatformCodec},
+    Action, Cond, Error, Result, RollupTx,
+};
+
+use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+use kv_session::{
+    rollup,
+    traits::{BumpVersion, KvSession, KvSnapshot, QueueIndex, QueueSession, Value},
+    RwTracker, Session,
+};
+use scale::{Decode, Encode};
+
+const DEFAULT_QUEUE_PREFIX: &[u8] = b"q/";
+const QUEUE_HEAD_KEY: &[u8] = b"_head";
+const QUEUE_TAIL_KEY: &[u8] = b"_tail";
+
+type KvStore = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Default)]
+struct AnchorState {
+    kv: KvStore,
+    replies: Vec<Vec<u8>>,
+    num_txs: u32,
+}
+
+/// A rollup anchor backed by an in-memory kv store
+///
+/// The anchor is a shared handle. Cloning it gives another handle to the same state.
+#[derive(Clone, Default)]
+pub struct MemoryAnchor {
+    state: Rc<RefCell<AnchorState>>,
+}
+
+impl MemoryAnchor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Reads a raw value from the kv store
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.state.borrow().kv.get(key).cloned()
+    }
+
+    /// Writes a raw value to the kv store directly, bypassing the rollup tx
+    pub fn set(&self, key: &[u8], value: Option<Vec<u8>>) {
+        let mut state = self.state.borrow_mut();
+        match value {
+            Some(v) => state.kv.insert(key.to_vec(), v),
+            None => state.kv.remove(key),
+        };
+    }
+
+    /// Pushes a request to the queue, as the consumer contract does
+    ///
+    /// Returns the index of the request.
+    pub fn push_message(&self, data: Vec<u8>) -> Result<QueueIndex> {
+        let mut state = self.state.borrow_mut();
+        let tail = read_index(&state.kv, QUEUE_TAIL_KEY)?;
+        state.kv.insert(queue_key(&tail.encode()), data);
+        state
+            .kv
+            .insert(queue_key(QUEUE_TAIL_KEY), (tail + 1).encode());
+        Ok(tail)
+    }
+
+    /// Returns the index of the first unprocessed request
+    pub fn queue_head(&self) -> Result<QueueIndex> {
+        read_index(&self.state.borrow().kv, QUEUE_HEAD_KEY)
+    }
+
+    /// Returns the index of the next request to push
+    pub fn queue_tail(&self) -> Result<QueueIndex> {
+        read_index(&self.state.borrow().kv, QUEUE_TAIL_KEY)
+    }
+
+    /// Returns all the replies received so far
+    pub fn replies(&self) -> Vec<Vec<u8>> {
+        self.state.borrow().replies.clone()
+    }
+
+    /// Returns the number of the applied rollup txs
+    pub fn num_txs(&self) -> u32 {
+        self.state.borrow().num_txs
+    }
+
+    /// Takes a snapshot of the current kv store
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let state = self.state.borrow();
+        MemorySnapshot {
+            kv: state.kv.clone(),
+            id: state.num_txs,
+        }
+    }
+
+    /// Applies a rollup tx atomically
+    ///
+    /// Checks the conditions, applies the updates, and then executes the actions. Nothing is
+    /// changed if any of the steps fails.
+    pub fn apply(&self, tx: &RollupTx) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let mut kv = state.kv.clone();
+        let mut replies = Vec::new();
+        // Check the conditions
+        for cond in &tx.conds {
+            if !check_cond(&kv, cond)? {
+                return Err(Error::CondNotMet);
+            }
+        }
+        // Apply the updates
+        for (key, value) in &tx.updates {
+            match value {
+                Some(v) => kv.insert(key.0.clone(), v.0.clone()),
+                None => kv.remove(&key.0),
+            };
+        }
+        // Apply the actions
+        for raw in &tx.actions {
+            let action = Action::decode(&mut &raw.0[..]).or(Err(Error::FailedToDecode))?;
+            match action {
+                Action::Reply(data) => replies.push(data),
+                Action::ProcessedTo(target) => pop_to(&mut kv, target)?,
+            }
+        }
+        state.kv = kv;
+        state.replies.append(&mut replies);
+        state.num_txs += 1;
+        Ok(())
+    }
+}
+
+fn check_cond(kv: &KvStore, cond: &Cond) -> Result<bool> {
+    let value = kv.get(&cond.key().0);
+    let met = match cond {
+        Cond::Eq(_, expected) => value == expected.as_ref().map(|v| &v.0),
+        Cond::Ne(_, expected) => value != expected.as_ref().map(|v| &v.0),
+        Cond::Exists(_) => value.is_some(),
+        Cond::NotExists(_) => value.is_none(),
+        Cond::Le(_, n) => decode_u32(value)? <= *n,
+        Cond::Ge(_, n) => decode_u32(value)? >= *n,
+    };
+    Ok(met)
+}
+
+fn decode_u32(value: Option<&Vec<u8>>) -> Result<u32> {
+    match value {
+        Some(v) => u32::decode(&mut &v[..]).or(Err(Error::FailedToDecode)),
+        None => Ok(0),
+    }
+}
+
+fn pop_to(kv: &mut KvStore, target: QueueIndex) -> Result<()> {
+    let head = read_index(kv, QUEUE_HEAD_KEY)?;
+    let tail = read_index(kv, QUEUE_TAIL_KEY)?;
+    if target > tail || target < head {
+        return Err(Error::InvalidPopTarget);
+    }
+    for idx in head..target {
+        kv.remove(&queue_key(&idx.encode()));
+    }
+    kv.insert(queue_key(QUEUE_HEAD_KEY), target.encode());
+    Ok(())
+}
+
+fn queue_key(key: &[u8]) -> Vec<u8> {
+    [DEFAULT_QUEUE_PREFIX, key].concat()
+}
+
+fn read_index(kv: &KvStore, key: &[u8]) -> Result<QueueIndex> {
+    match kv.get(&queue_key(key)) {
+        Some(v) => QueueIndex::decode(&mut &v[..]).or(Err(Error::FailedToDecode)),
+        None => Ok(0),
+    }
+}
+
+/// A snapshot of the `MemoryAnchor` kv store
+pub struct MemorySnapshot {
+    kv: KvStore,
+    id: u32,
+}
+
+impl KvSnapshot for MemorySnapshot {
+    fn get(&self, key: &[u8]) -> kv_session::Result<Option<Value>> {
+        Ok(self.kv.get(key).cloned())
+    }
+
+    fn snapshot_id(&self) -> kv_session::Result<Vec<u8>> {
+        Ok(self.id.encode())
+    }
+}
+
+impl BumpVersion for MemorySnapshot {
+    fn bump_version(&self, version: Option<Vec<u8>>) -> kv_session::Result<Vec<u8>> {
+        Ink::bump_version(version.as_deref()).or(Err(kv_session::Error::FailedToDecode))
+    }
+}
+
+impl LockVersionReader for MemorySnapshot {
+    fn get_version(&self, key: &[u8]) -> Result<LockVersion> {
+        lock::read_version::<Ink>(self, key)
+    }
+}
+
+pub type ScaleCodec = PlatformCodec<Ink>;
+
+/// A rollup client connected to a `MemoryAnchor`
+///
+/// Actions are SCALE encoded, the same as the Substrate client.
+pub struct MemoryRollupClient {
+    anchor: MemoryAnchor,
+    actions: ActionLog,
+    conds: Vec<Cond>,
+    locks: Vec<LockAccess<Ink>>,
+    tx_limit: TxLimit,
+    session: Session<MemorySnapshot, RwTracker, ScaleCodec>,
+}
+
+pub struct SubmittableRollupTx {
+    anchor: MemoryAnchor,
+    tx: RollupTx,
+}
+
+impl MemoryRollupClient {
+    pub fn new(anchor: &MemoryAnchor) -> Result<Self> {
+        let kvdb = anchor.snapshot();
+        let access_tracker = RwTracker::new();
+        Ok(Self {
+            anchor: anchor.clone(),
+            actions: Default::default(),
+            conds: Default::default(),
+            locks: Default::default(),
+            tx_limit: Default::default(),
+            session: Session::new(kvdb, access_tracker, DEFAULT_QUEUE_PREFIX)
+                .map_err(Error::SessionError)?,
+        })
+    }
+
+    /// Splits the rollup tx if its SCALE encoded size exceeds `max_tx_size` bytes
+    pub fn with_max_tx_size(mut self, max_tx_size: usize) -> Self {
+        self.tx_limit.max_size = Some(max_tx_size);
+        self
+    }
+
+    fn convert_err(err: kv_session::Error) -> Error {
+        match err {
+            kv_session::Error::FailedToDecode => Error::SessionFailedToDecode,
+            kv_session::Error::FailedToGetStorage => Error::SessionFailedToGetStorage,
+        }
+    }
+}
+
+impl RollupClient for MemoryRollupClient {
+    type Submittable = SubmittableRollupTx;
+    type Platform = Ink;
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.session.get(key).map_err(Self::convert_err)
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        self.session.put(key, value);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.session.delete(key);
+    }
+
+    fn pop(&mut self) -> Result<Option<Vec<u8>>> {
+        let request = self.session.pop().map_err(Self::convert_err)?;
+        if request.is_some() {
+            self.actions.popped();
+        }
+        Ok(request)
+    }
+
+    fn action(&mut self, action: Action) -> &mut Self {
+        self.actions.push(action);
+        self
+    }
+
+    fn cond(&mut self, cond: Cond) -> &mut Self {
+        self.conds.push(cond);
+        self
+    }
+
+    fn lock(&mut self, access: LockAccess<Self::Platform>) -> &mut Self {
+        self.locks.push(access);
+        self
+    }
+
+    fn commit_split(self) -> Result<Vec<SubmittableRollupTx>> {
+        let (session_tx, kvdb) = self.session.commit();
+        let raw_tx = rollup::rollup(
+            &kvdb,
+            session_tx,
+            rollup::VersionLayout::Standalone {
+                key_postfix: b":ver".to_vec(),
+            },
+        )
+        .map_err(Self::convert_err)?;
+
+        if raw_tx.updates.is_empty() && self.actions.is_empty() && raw_tx.queue_head.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let mut tx = RollupTx {
+            conds: raw_tx
+                .conditions
+                .into_iter()
+                .map(|(k, v)| Cond::Eq(k.into(), v.map(Into::into)))
+                .chain(self.conds)
+                .collect(),
+            actions: Vec::new(),
+            updates: raw_tx
+                .updates
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.map(Into::into)))
+                .collect(),
+        };
+        for access in &self.locks {
+            access.apply(&mut tx, &kvdb)?;
+        }
+        tx.normalize()?;
+
+        let chunks = split::split(
+            tx.conds,
+            tx.updates,
+            self.actions,
+            raw_tx.queue_head,
+            self.tx_limit,
+            |head| Cond::Eq(queue_key(QUEUE_HEAD_KEY).into(), Some(head.encode().into())),
+            |chunk| (&chunk.conds, &chunk.updates, &chunk.actions).encoded_size(),
+        );
+
+        let anchor = self.anchor;
+        Ok(chunks
+            .into_iter()
+            .map(|chunk| SubmittableRollupTx {
+                anchor: anchor.clone(),
+                tx: RollupTx {
+                    conds: chunk.conds,
+                    actions: chunk.actions.iter().map(|a| a.encode().into()).collect(),
+                    updates: chunk.updates,
+                },
+            })
+            .collect())
+    }
+}
+
+impl SubmittableRollupTx {
+    /// Returns the committed rollup tx
+    pub fn tx(&self) -> &RollupTx {
+        &self.tx
+    }
+}
+
+impl Submittable for SubmittableRollupTx {
+    /// Applies the tx to the anchor. The key is ignored.
+    ///
+    /// Returns the SCALE encoded sequence number of the tx as its hash.
+    fn submit(self, _secret_key: &[u8; 32]) -> Result<Vec<u8>> {
+        self.anchor.apply(&self.tx)?;
+        Ok(self.anchor.num_txs().encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [1u8; 32];
+
+    fn submit(client: MemoryRollupClient) -> Result<Vec<u8>> {
+        client
+            .commit()
+            .expect("commit should succeed")
+            .expect("tx should not be empty")
+            .submit(&KEY)
+    }
+
+    #[test]
+    fn kv_works() {
+        let anchor = MemoryAnchor::new();
+
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        assert_eq!(client.get(b"k").unwrap(), None);
+        client.put(b"k", b"v".to_vec());
+        submit(client).expect("submit should succeed");
+        assert_eq!(anchor.get(b"k"), Some(b"v".to_vec()));
+
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        assert_eq!(client.get(b"k").unwrap(), Some(b"v".to_vec()));
+        client.delete(b"k");
+        submit(client).expect("submit should succeed");
+        assert_eq!(anchor.get(b"k"), None);
+    }
+
+    #[test]
+    fn request_response_works() {
+        let anchor = MemoryAnchor::new();
+        anchor.push_message(b"req0".to_vec()).unwrap();
+        anchor.push_message(b"req1".to_vec()).unwrap();
+
+        // Answer the first request
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        let req = client.pop().unwrap().expect("request should be queued");
+        assert_eq!(req, b"req0".to_vec());
+        client.action(Action::Reply(b"resp0".to_vec()));
+        submit(client).expect("submit should succeed");
+        assert_eq!(anchor.replies(), vec![b"resp0".to_vec()]);
+        assert_eq!(anchor.queue_head().unwrap(), 1);
+
+        // Then the second one
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        let req = client.pop().unwrap().expect("request should be queued");
+        assert_eq!(req, b"req1".to_vec());
+        client.action(Action::Reply(b"resp1".to_vec()));
+        submit(client).expect("submit should succeed");
+        assert_eq!(anchor.queue_head().unwrap(), 2);
+        assert_eq!(anchor.queue_tail().unwrap(), 2);
+
+        // The queue is drained
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        assert_eq!(client.pop().unwrap(), None);
+    }
+
+    #[test]
+    fn pop_many_works() {
+        let anchor = MemoryAnchor::new();
+        for i in 0..5u8 {
+            anchor.push_message(vec![i]).unwrap();
+        }
+
+        // Answer a batch of requests by a single tx
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        let requests = client.pop_many(3).unwrap();
+        assert_eq!(requests, vec![vec![0], vec![1], vec![2]]);
+        for req in requests {
+            client.action(Action::Reply([b"resp".to_vec(), req].concat()));
+        }
+        let tx = client
+            .commit()
+            .expect("commit should succeed")
+            .expect("tx should not be empty");
+        let actions: Vec<Action> = tx
+            .tx()
+            .actions
+            .iter()
+            .map(|a| Action::decode(&mut &a.0[..]).unwrap())
+            .collect();
+        assert_eq!(actions.len(), 4);
+        assert_eq!(actions[3], Action::ProcessedTo(3));
+        tx.submit(&KEY).expect("submit should succeed");
+        assert_eq!(anchor.num_txs(), 1);
+        assert_eq!(anchor.replies().len(), 3);
+        assert_eq!(anchor.queue_head().unwrap(), 3);
+
+        // Only the rest of the requests are left
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        assert_eq!(client.pop_many(10).unwrap(), vec![vec![3], vec![4]]);
+        assert!(client.pop_many(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn conflict_is_rejected() {
+        let anchor = MemoryAnchor::new();
+
+        // Two sessions read-modify-write the same key concurrently
+        let mut client1 = MemoryRollupClient::new(&anchor).unwrap();
+        let mut client2 = MemoryRollupClient::new(&anchor).unwrap();
+        assert_eq!(client1.get(b"counter").unwrap(), None);
+        assert_eq!(client2.get(b"counter").unwrap(), None);
+        client1.put(b"counter", 1u32.encode());
+        client2.put(b"counter", 2u32.encode());
+
+        // The first one wins
+        submit(client1).expect("first writer should win");
+        assert!(matches!(submit(client2), Err(Error::CondNotMet)));
+        assert_eq!(anchor.get(b"counter"), Some(1u32.encode()));
+        assert_eq!(anchor.num_txs(), 1);
+    }
+
+    #[test]
+    fn extra_conds_work() {
+        let anchor = MemoryAnchor::new();
+        anchor.push_message(b"req0".to_vec()).unwrap();
+
+        // Only create if missing
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        client.cond(Cond::NotExists(b"k".to_vec().into()));
+        client.put(b"k", b"v".to_vec());
+        submit(client).expect("key should be missing");
+
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        client.cond(Cond::NotExists(b"k".to_vec().into()));
+        client.put(b"k", b"v2".to_vec());
+        assert!(matches!(submit(client), Err(Error::CondNotMet)));
+
+        // Only apply if the queue tail is at least 1
+        let tail_key = queue_key(QUEUE_TAIL_KEY);
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        client
+            .cond(Cond::Ge(tail_key.clone().into(), 1))
+            .cond(Cond::Le(tail_key.clone().into(), 1))
+            .cond(Cond::Exists(b"k".to_vec().into()))
+            .cond(Cond::Ne(b"k".to_vec().into(), None))
+            .action(Action::Reply(b"ok".to_vec()));
+        submit(client).expect("conditions should be met");
+
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        client
+            .cond(Cond::Ge(tail_key.into(), 2))
+            .action(Action::Reply(b"too early".to_vec()));
+        assert!(matches!(submit(client), Err(Error::CondNotMet)));
+        assert_eq!(anchor.replies(), vec![b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn split_tx_drains_queue() {
+        let anchor = MemoryAnchor::new();
+        for i in 0..10u8 {
+            anchor.push_message(vec![i]).unwrap();
+        }
+
+        // Answer all the requests with a tight size limit
+        let mut client = MemoryRollupClient::new(&anchor)
+            .unwrap()
+            .with_max_tx_size(100);
+        while let Some(req) = client.pop().unwrap() {
+            client.action(Action::Reply([b"resp".to_vec(), req].concat()));
+        }
+        let txs = client.commit_split().expect("commit should succeed");
+        assert!(txs.len() > 1);
+
+        // The txs only apply in order
+        let mut txs = txs.into_iter();
+        let first = txs.next().unwrap();
+        let second = txs.next().unwrap();
+        assert!(matches!(second.submit(&KEY), Err(Error::CondNotMet)));
+        first.submit(&KEY).expect("first tx should apply");
+        for tx in txs {
+            assert!(matches!(tx.submit(&KEY), Err(Error::CondNotMet)));
+        }
+
+        // The rest of the requests are left to the next sessions
+        while anchor.queue_head().unwrap() < 10 {
+            let mut client = MemoryRollupClient::new(&anchor)
+                .unwrap()
+                .with_max_tx_size(100);
+            while let Some(req) = client.pop().unwrap() {
+                client.action(Action::Reply([b"resp".to_vec(), req].concat()));
+            }
+            submit(client).expect("submit should succeed");
+        }
+        let expected: Vec<_> = (0..10u8)
+            .map(|i| [b"resp".to_vec(), vec![i]].concat())
+            .collect();
+        assert_eq!(anchor.replies(), expected);
+    }
+
+    #[test]
+    fn never_split_tx_with_updates() {
+        let anchor = MemoryAnchor::new();
+        for i in 0..10u8 {
+            anchor.push_message(vec![i]).unwrap();
+        }
+
+        let mut client = MemoryRollupClient::new(&anchor)
+            .unwrap()
+            .with_max_tx_size(100);
+        client.put(b"k", b"v".to_vec());
+        while let Some(req) = client.pop().unwrap() {
+            client.action(Action::Reply([b"resp".to_vec(), req].concat()));
+        }
+        let txs = client.commit_split().expect("commit should succeed");
+        assert_eq!(txs.len(), 1);
+        txs.into_iter().next().unwrap().submit(&KEY).unwrap();
+        assert_eq!(anchor.get(b"k"), Some(b"v".to_vec()));
+        assert_eq!(anchor.queue_head().unwrap(), 10);
+    }
+
+    #[test]
+    fn locks_work() {
+        use crate::lock::{InkLocks, GLOBAL};
+
+        let mut locks = InkLocks::default();
+        locks.add("a", GLOBAL).unwrap();
+        locks.add_family("balance", GLOBAL).unwrap();
+        let anchor = MemoryAnchor::new();
+
+        // Concurrent sessions writing the same lock conflict
+        let mut client1 = MemoryRollupClient::new(&anchor).unwrap();
+        let mut client2 = MemoryRollupClient::new(&anchor).unwrap();
+        client1
+            .lock(locks.write("a").unwrap())
+            .put(b"k1", b"1".to_vec());
+        client2
+            .lock(locks.write("a").unwrap())
+            .put(b"k2", b"2".to_vec());
+        submit(client1).expect("first writer should win");
+        assert!(matches!(submit(client2), Err(Error::CondNotMet)));
+
+        // Locks with different parameters don't conflict, but their family does
+        let mut client1 = MemoryRollupClient::new(&anchor).unwrap();
+        let mut client2 = MemoryRollupClient::new(&anchor).unwrap();
+        let mut client3 = MemoryRollupClient::new(&anchor).unwrap();
+        client1
+            .lock(locks.write_param("balance", b"alice").unwrap())
+            .action(Action::Reply(b"alice".to_vec()));
+        client2
+            .lock(locks.write_param("balance", b"bob").unwrap())
+            .action(Action::Reply(b"bob".to_vec()));
+        client3
+            .lock(locks.read("balance").unwrap())
+            .action(Action::Reply(b"all".to_vec()));
+        submit(client1).expect("alice should be unlocked");
+        submit(client2).expect("bob should be unlocked");
+        assert!(matches!(submit(client3), Err(Error::CondNotMet)));
+        assert_eq!(anchor.replies(), vec![b"alice".to_vec(), b"bob".to_vec()]);
+
+        // Read the family again from the latest snapshot
+        let mut client = MemoryRollupClient::new(&anchor).unwrap();
+        client
+            .lock(locks.read("balance").unwrap())
+            .lock(locks.read("a").unwrap())
+            .action(Action::Reply(b"all".to_vec()));
+        submit(client).expect("the family should be unlocked");
+    }
+
+    #[test]
+    fn invalid_pop_target_reverts_tx() {
+        let anchor = MemoryAnchor::new();
+        let mut tx = RollupTx::default();
+        tx.updates
+            .push((b"k".to_vec().into(), Some(b"v".to_vec().into())));
+        tx.action(Action::ProcessedTo(1).encode());
+
+        assert!(matches!(anchor.apply(&tx), Err(Error::InvalidPopTarget)));
+        assert_eq!(anchor.get(b"k"), None);
+        assert_eq!(anchor.num_txs(), 0);
+    }
+}